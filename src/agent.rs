@@ -0,0 +1,573 @@
+//! An agent holding private keys in a single process, signing for other processes over a Unix
+//! domain socket, similarly to `ssh-agent`.
+//!
+//! Requests and responses are JSON documents, one per line.
+use crate::{base64_decode, base64_encode, public_key_from_pem, Algorithm, SigningKey};
+use anyhow::Context;
+use openssl::{
+    ec::EcKey,
+    pkey::{PKey, Public},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::{
+        fs::FileTypeExt,
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+/// The environment variable holding the path of the socket of the agent.
+pub const AUTH_SOCK_ENV: &str = "TLSIGN_AUTH_SOCK";
+
+/// How long the agent waits for a client before hanging up, so that one cannot block the others.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Set by the handler of the signals stopping the agent.
+static STOPPED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Request {
+    List,
+    Sign { kid: String, payload: String },
+    PublicKey { kid: String },
+    Lock { passphrase: String },
+    Unlock { passphrase: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Response {
+    Keys(Vec<AgentKeyInfo>),
+    /// The base64 encoded raw JWS signature.
+    Signature(String),
+    /// The PEM encoded public key.
+    PublicKey(String),
+    Ok,
+    Error(String),
+}
+
+/// A key held by an agent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AgentKeyInfo {
+    pub kid: String,
+    pub alg: Algorithm,
+    /// How many seconds are left before the key is removed from the agent, if it has a lifetime.
+    pub expires_in: Option<u64>,
+}
+
+struct AgentEntry {
+    kid: String,
+    key: Box<dyn SigningKey>,
+    expires: Option<Instant>,
+}
+
+/// Holds private keys and signs with them for the clients connecting to its socket.
+#[derive(Default)]
+pub struct Agent {
+    keys: Vec<AgentEntry>,
+    /// The salt and the SHA-256 digest of the salted passphrase the agent is locked with.
+    lock: Option<([u8; 16], [u8; 32])>,
+}
+
+impl Agent {
+    /// Hold `key` as `kid`, removing it once `lifetime` has elapsed if provided.
+    pub fn add_key(
+        &mut self,
+        kid: impl Into<String>,
+        key: impl SigningKey + 'static,
+        lifetime: Option<Duration>,
+    ) -> Result<(), anyhow::Error> {
+        let kid = kid.into();
+        if self.keys.iter().any(|entry| entry.kid == kid) {
+            return Err(anyhow::anyhow!(
+                "The agent already holds a key with kid {}.",
+                kid
+            ));
+        }
+        self.keys.push(AgentEntry {
+            kid,
+            key: Box::new(key),
+            expires: lifetime.map(|lifetime| Instant::now() + lifetime),
+        });
+        Ok(())
+    }
+
+    /// Create the Unix socket of the agent at `path`, only accessible to the current user. A socket
+    /// left behind by a stopped agent is replaced, but any other file is left alone.
+    pub fn bind(path: &Path) -> Result<UnixListener, anyhow::Error> {
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(anyhow::anyhow!(
+                    "{} exists and is not a socket.",
+                    path.display()
+                ));
+            }
+            if UnixStream::connect(path).is_ok() {
+                return Err(anyhow::anyhow!(
+                    "An agent is already listening on {}.",
+                    path.display()
+                ));
+            }
+            // Left behind by an agent which was stopped
+            std::fs::remove_file(path).context("Failed to remove the stale agent socket.")?;
+        }
+        // Safety: `umask` cannot fail, and is restored right after creating the socket
+        let umask = unsafe { libc::umask(0o177) };
+        let listener = UnixListener::bind(path);
+        unsafe { libc::umask(umask) };
+        listener.with_context(|| format!("Failed to listen on {}.", path.display()))
+    }
+
+    /// Answer the requests of the clients connecting to `listener`, until the agent is stopped by
+    /// `SIGINT`, `SIGTERM` or `SIGHUP`, or every key it held has expired. The socket is then
+    /// removed.
+    pub fn serve(mut self, listener: UnixListener) -> Result<(), anyhow::Error> {
+        let handler = on_stop_signal as extern "C" fn(libc::c_int);
+        for signal in &[libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            // Safety: the handler only sets an atomic flag
+            unsafe { libc::signal(*signal, handler as libc::sighandler_t) };
+        }
+        let result = self.accept_clients(&listener);
+        if let Some(path) = listener.local_addr()?.as_pathname() {
+            let _ = std::fs::remove_file(path);
+        }
+        result
+    }
+
+    fn accept_clients(&mut self, listener: &UnixListener) -> Result<(), anyhow::Error> {
+        let had_keys = !self.keys.is_empty();
+        while !STOPPED.load(Ordering::SeqCst) {
+            // Expired keys are removed as soon as they expire, not only when a client connects
+            self.remove_expired_keys();
+            if had_keys && self.keys.is_empty() {
+                eprintln!("Every key has expired, stopping.");
+                break;
+            }
+            if wait_for_client(listener, self.next_expiry())? {
+                if let Ok((stream, _)) = listener.accept() {
+                    // A misbehaving client must not stop the agent
+                    let _ = self.serve_client(stream);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_expired_keys(&mut self) {
+        let now = Instant::now();
+        self.keys
+            .retain(|entry| entry.expires.is_none_or(|expires| expires > now));
+    }
+
+    /// When the first key to expire does.
+    fn next_expiry(&self) -> Option<Instant> {
+        self.keys.iter().filter_map(|entry| entry.expires).min()
+    }
+
+    fn serve_client(&mut self, stream: UnixStream) -> Result<(), anyhow::Error> {
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let response = match serde_json::from_str(&line?) {
+                Ok(request) => self.respond(request),
+                Err(error) => Response::Error(format!("Invalid request: {}", error)),
+            };
+            let mut response = serde_json::to_vec(&response)?;
+            response.push(b'\n');
+            writer.write_all(&response)?;
+        }
+        Ok(())
+    }
+
+    fn respond(&mut self, request: Request) -> Response {
+        self.remove_expired_keys();
+        let now = Instant::now();
+
+        let result = match request {
+            Request::Lock { passphrase } => self.lock(&passphrase),
+            Request::Unlock { passphrase } => self.unlock(&passphrase),
+            _ if self.lock.is_some() => Err(anyhow::anyhow!("The agent is locked.")),
+            Request::List => Ok(Response::Keys(
+                self.keys
+                    .iter()
+                    .filter_map(|entry| {
+                        Some(AgentKeyInfo {
+                            kid: entry.kid.clone(),
+                            alg: entry.key.alg().ok()?,
+                            expires_in: entry
+                                .expires
+                                .map(|expires| expires.duration_since(now).as_secs()),
+                        })
+                    })
+                    .collect(),
+            )),
+            Request::Sign { kid, payload } => self.key(&kid).and_then(|key| {
+                let payload =
+                    base64_decode(&payload).context("Failed to base64 decode the payload.")?;
+                Ok(Response::Signature(base64_encode(&key.sign(&payload)?)))
+            }),
+            Request::PublicKey { kid } => self.key(&kid).and_then(|key| {
+                let public_key = PKey::from_ec_key(key.public_key()?)?;
+                Ok(Response::PublicKey(String::from_utf8(
+                    public_key.public_key_to_pem()?,
+                )?))
+            }),
+        };
+        result.unwrap_or_else(|error| Response::Error(format!("{:#}", error)))
+    }
+
+    fn key(&self, kid: &str) -> Result<&dyn SigningKey, anyhow::Error> {
+        self.keys
+            .iter()
+            .find(|entry| entry.kid == kid)
+            .map(|entry| &*entry.key)
+            .ok_or_else(|| anyhow::anyhow!("The agent holds no key with kid {}.", kid))
+    }
+
+    fn lock(&mut self, passphrase: &str) -> Result<Response, anyhow::Error> {
+        if self.lock.is_some() {
+            return Err(anyhow::anyhow!("The agent is already locked."));
+        }
+        let mut salt = [0; 16];
+        openssl::rand::rand_bytes(&mut salt)?;
+        self.lock = Some((salt, salted_digest(&salt, passphrase)));
+        Ok(Response::Ok)
+    }
+
+    fn unlock(&mut self, passphrase: &str) -> Result<Response, anyhow::Error> {
+        match &self.lock {
+            None => Err(anyhow::anyhow!("The agent is not locked.")),
+            Some((salt, digest)) => {
+                if !openssl::memcmp::eq(&salted_digest(salt, passphrase), digest) {
+                    return Err(anyhow::anyhow!("Incorrect passphrase."));
+                }
+                self.lock = None;
+                Ok(Response::Ok)
+            }
+        }
+    }
+}
+
+extern "C" fn on_stop_signal(_: libc::c_int) {
+    STOPPED.store(true, Ordering::SeqCst);
+}
+
+/// Wait for a client to connect until `deadline`, if any, returning whether one did. A signal
+/// interrupts the wait.
+fn wait_for_client(
+    listener: &UnixListener,
+    deadline: Option<Instant>,
+) -> Result<bool, anyhow::Error> {
+    let timeout = match deadline {
+        Some(deadline) => {
+            // Rounded up, so as not to wake up right before the deadline
+            let millis = deadline
+                .saturating_duration_since(Instant::now())
+                .as_millis()
+                + 1;
+            millis.min(libc::c_int::MAX as u128) as libc::c_int
+        }
+        None => -1,
+    };
+    let mut pollfd = libc::pollfd {
+        fd: listener.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // Safety: `pollfd` is a single valid entry
+    match unsafe { libc::poll(&mut pollfd, 1, timeout) } {
+        -1 => {
+            let error = std::io::Error::last_os_error();
+            if error.kind() == std::io::ErrorKind::Interrupted {
+                Ok(false)
+            } else {
+                Err(error).context("Failed to wait for clients.")
+            }
+        }
+        ready => Ok(ready > 0),
+    }
+}
+
+fn salted_digest(salt: &[u8], passphrase: &str) -> [u8; 32] {
+    let mut sha256 = openssl::sha::Sha256::new();
+    sha256.update(salt);
+    sha256.update(passphrase.as_bytes());
+    sha256.finish()
+}
+
+/// A client of the agent listening on a socket.
+#[derive(Debug, Clone)]
+pub struct AgentClient {
+    socket: PathBuf,
+}
+
+impl AgentClient {
+    pub fn new(socket: impl Into<PathBuf>) -> Self {
+        AgentClient {
+            socket: socket.into(),
+        }
+    }
+
+    /// The keys held by the agent.
+    pub fn list(&self) -> Result<Vec<AgentKeyInfo>, anyhow::Error> {
+        match self.request(&Request::List)? {
+            Response::Keys(keys) => Ok(keys),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Lock the agent with `passphrase`, refusing to list keys or sign until it is unlocked.
+    pub fn lock(&self, passphrase: &str) -> Result<(), anyhow::Error> {
+        match self.request(&Request::Lock {
+            passphrase: passphrase.to_owned(),
+        })? {
+            Response::Ok => Ok(()),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Unlock the agent with the passphrase it was locked with.
+    pub fn unlock(&self, passphrase: &str) -> Result<(), anyhow::Error> {
+        match self.request(&Request::Unlock {
+            passphrase: passphrase.to_owned(),
+        })? {
+            Response::Ok => Ok(()),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// The key held by the agent as `kid`, to sign with.
+    pub fn key(&self, kid: &str) -> Result<AgentKey, anyhow::Error> {
+        let info = self
+            .list()?
+            .into_iter()
+            .find(|info| info.kid == kid)
+            .ok_or_else(|| anyhow::anyhow!("The agent holds no key with kid {}.", kid))?;
+        Ok(AgentKey {
+            client: self.clone(),
+            kid: info.kid,
+            alg: info.alg,
+        })
+    }
+
+    fn request(&self, request: &Request) -> Result<Response, anyhow::Error> {
+        let mut stream = UnixStream::connect(&self.socket).with_context(|| {
+            format!(
+                "Failed to connect to the agent on {}, is `tlsign agent` running?",
+                self.socket.display()
+            )
+        })?;
+        let mut request = serde_json::to_vec(request)?;
+        request.push(b'\n');
+        stream.write_all(&request)?;
+
+        let mut response = String::new();
+        BufReader::new(stream).read_line(&mut response)?;
+        match serde_json::from_str(&response).context("Invalid response from the agent.")? {
+            Response::Error(error) => Err(anyhow::anyhow!(error)),
+            response => Ok(response),
+        }
+    }
+}
+
+fn unexpected_response() -> anyhow::Error {
+    anyhow::anyhow!("Unexpected response from the agent.")
+}
+
+/// A private key held by an agent, which signs without the key leaving it.
+pub struct AgentKey {
+    client: AgentClient,
+    kid: String,
+    alg: Algorithm,
+}
+
+impl SigningKey for AgentKey {
+    fn alg(&self) -> Result<Algorithm, anyhow::Error> {
+        Ok(self.alg)
+    }
+
+    fn public_key(&self) -> Result<EcKey<Public>, anyhow::Error> {
+        match self.client.request(&Request::PublicKey {
+            kid: self.kid.clone(),
+        })? {
+            Response::PublicKey(pem) => public_key_from_pem(pem.as_bytes()),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    fn sign(&self, payload: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        match self.client.request(&Request::Sign {
+            kid: self.kid.clone(),
+            payload: base64_encode(payload),
+        })? {
+            Response::Signature(signature) => base64_decode(&signature)
+                .context("Failed to base64 decode the signature from the agent."),
+            _ => Err(unexpected_response()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generate_key, verify_ecdsa};
+    use std::{sync::atomic::AtomicUsize, thread};
+
+    const KID: &str = "45fc75cf-5649-4134-84b3-192c2c78e990";
+
+    /// A socket path of its own for each agent of the tests.
+    fn socket() -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        std::env::temp_dir().join(format!(
+            "tlsign-agent-{}-{}.sock",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ))
+    }
+
+    /// Serve an agent holding an ES256 key for each kid, with its lifetime.
+    fn serve(
+        keys: &[(&str, Option<Duration>)],
+    ) -> (AgentClient, thread::JoinHandle<Result<(), anyhow::Error>>) {
+        let path = socket();
+        let listener = Agent::bind(&path).unwrap();
+        let keys: Vec<_> = keys
+            .iter()
+            .map(|(kid, lifetime)| (kid.to_string(), *lifetime))
+            .collect();
+        // The keys are added on the thread of the agent, as a `SigningKey` need not be `Send`
+        let server = thread::spawn(move || {
+            let mut agent = Agent::default();
+            for (kid, lifetime) in keys {
+                agent.add_key(kid, generate_key(Algorithm::ES256)?, lifetime)?;
+            }
+            agent.serve(listener)
+        });
+        (AgentClient::new(path), server)
+    }
+
+    #[test]
+    fn sign_with_a_held_key() {
+        let (client, _) = serve(&[(KID, None)]);
+        assert_eq!(
+            client.list().unwrap(),
+            [AgentKeyInfo {
+                kid: KID.to_owned(),
+                alg: Algorithm::ES256,
+                expires_in: None,
+            }]
+        );
+        let key = client.key(KID).unwrap();
+        let signature = key.sign(b"payload").unwrap();
+        let public_key = key.public_key().unwrap();
+        assert!(verify_ecdsa(b"payload", &signature, Algorithm::ES256, &public_key).unwrap());
+
+        assert_eq!(
+            client.key("unknown").err().unwrap().to_string(),
+            "The agent holds no key with kid unknown."
+        );
+        let unknown = AgentKey {
+            client: client.clone(),
+            kid: "unknown".to_owned(),
+            alg: Algorithm::ES256,
+        };
+        assert_eq!(
+            unknown.sign(b"payload").unwrap_err().to_string(),
+            "The agent holds no key with kid unknown."
+        );
+        std::fs::remove_file(&client.socket).unwrap();
+    }
+
+    #[test]
+    fn lock_and_unlock() {
+        let (client, _) = serve(&[(KID, None)]);
+        let key = client.key(KID).unwrap();
+        assert_eq!(
+            client.unlock("secret").unwrap_err().to_string(),
+            "The agent is not locked."
+        );
+        client.lock("secret").unwrap();
+        assert_eq!(
+            client.lock("other").unwrap_err().to_string(),
+            "The agent is already locked."
+        );
+
+        // Nothing is listed nor signed while locked
+        assert_eq!(
+            client.list().unwrap_err().to_string(),
+            "The agent is locked."
+        );
+        assert_eq!(
+            key.sign(b"payload").unwrap_err().to_string(),
+            "The agent is locked."
+        );
+        assert_eq!(
+            client.unlock("wrong").unwrap_err().to_string(),
+            "Incorrect passphrase."
+        );
+        assert!(key.sign(b"payload").is_err());
+
+        client.unlock("secret").unwrap();
+        key.sign(b"payload").unwrap();
+        std::fs::remove_file(&client.socket).unwrap();
+    }
+
+    #[test]
+    fn remove_expired_keys() {
+        let (client, _) = serve(&[(KID, None), ("expiring", Some(Duration::from_millis(500)))]);
+        let expiring = client.key("expiring").unwrap();
+        expiring.sign(b"payload").unwrap();
+        assert_eq!(client.list().unwrap().len(), 2);
+
+        thread::sleep(Duration::from_millis(700));
+        let keys = client.list().unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].kid, KID);
+        assert_eq!(
+            expiring.sign(b"payload").unwrap_err().to_string(),
+            "The agent holds no key with kid expiring."
+        );
+        std::fs::remove_file(&client.socket).unwrap();
+    }
+
+    #[test]
+    fn stop_once_every_key_has_expired() {
+        let (client, server) = serve(&[(KID, Some(Duration::from_millis(200)))]);
+        assert_eq!(client.list().unwrap()[0].expires_in, Some(0));
+        assert!(client.socket.exists());
+
+        // The agent stops without any client connecting, and removes its socket
+        server.join().unwrap().unwrap();
+        assert!(!client.socket.exists());
+        assert!(client.list().is_err());
+    }
+
+    #[test]
+    fn refuse_to_replace_other_files() {
+        let path = socket();
+        std::fs::write(&path, "notes").unwrap();
+        assert_eq!(
+            Agent::bind(&path).unwrap_err().to_string(),
+            format!("{} exists and is not a socket.", path.display())
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "notes");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn refuse_to_bind_the_socket_of_a_running_agent() {
+        let (client, _) = serve(&[(KID, None)]);
+        assert!(Agent::bind(&client.socket)
+            .unwrap_err()
+            .to_string()
+            .starts_with("An agent is already listening on"));
+        std::fs::remove_file(&client.socket).unwrap();
+    }
+}
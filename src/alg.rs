@@ -0,0 +1,96 @@
+use openssl::{hash::MessageDigest, nid::Nid};
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+/// A JWS algorithm using ECDSA, as defined in section 3.4 of RFC7518.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Algorithm {
+    /// ECDSA using P-256 and SHA-256.
+    ES256,
+    /// ECDSA using P-384 and SHA-384.
+    ES384,
+    /// ECDSA using P-521 and SHA-512.
+    ES512,
+}
+
+impl Algorithm {
+    /// The algorithm signing with keys on the given elliptic curve.
+    pub fn from_curve(curve: Option<Nid>) -> Result<Self, anyhow::Error> {
+        match curve {
+            Some(Nid::X9_62_PRIME256V1) => Ok(Algorithm::ES256),
+            Some(Nid::SECP384R1) => Ok(Algorithm::ES384),
+            Some(Nid::SECP521R1) => Ok(Algorithm::ES512),
+            _ => Err(anyhow::anyhow!(
+                "The underlying elliptic curve must be one of P-256, P-384 or P-521."
+            )),
+        }
+    }
+
+    /// The `alg` header value.
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::ES256 => "ES256",
+            Algorithm::ES384 => "ES384",
+            Algorithm::ES512 => "ES512",
+        }
+    }
+
+    /// The elliptic curve of the keys signing with this algorithm.
+    pub fn curve(self) -> Nid {
+        match self {
+            Algorithm::ES256 => Nid::X9_62_PRIME256V1,
+            Algorithm::ES384 => Nid::SECP384R1,
+            Algorithm::ES512 => Nid::SECP521R1,
+        }
+    }
+
+    /// The NIST name of the elliptic curve, e.g. `P-521`.
+    pub fn curve_name(self) -> &'static str {
+        match self {
+            Algorithm::ES256 => "P-256",
+            Algorithm::ES384 => "P-384",
+            Algorithm::ES512 => "P-521",
+        }
+    }
+
+    /// The digest applied to the payload before signing.
+    pub fn digest(self) -> MessageDigest {
+        match self {
+            Algorithm::ES256 => MessageDigest::sha256(),
+            Algorithm::ES384 => MessageDigest::sha384(),
+            Algorithm::ES512 => MessageDigest::sha512(),
+        }
+    }
+
+    /// The length in bytes of each of the `R` and `S` signature coordinates,
+    /// the signature itself being twice as long.
+    pub fn coordinate_len(self) -> usize {
+        match self {
+            Algorithm::ES256 => 32,
+            Algorithm::ES384 => 48,
+            Algorithm::ES512 => 66,
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ES256" => Ok(Algorithm::ES256),
+            "ES384" => Ok(Algorithm::ES384),
+            "ES512" => Ok(Algorithm::ES512),
+            _ => Err(anyhow::anyhow!(
+                "Unsupported JWS algorithm `{}`, expected one of ES256, ES384 or ES512.",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
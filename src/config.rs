@@ -0,0 +1,515 @@
+//! Named profiles, read from `~/.config/tlsign/config.toml`, holding the key, `kid` and
+//! environment to sign for, e.g.
+//!
+//! ```toml
+//! default_profile = "sandbox"
+//!
+//! [profiles.sandbox]
+//! key = "~/keys/sandbox.pem"
+//! kid = "45fc75cf-5649-4134-84b3-192c2c78e990"
+//! url = "https://api.truelayer-sandbox.com"
+//!
+//! [profiles.acme-production]
+//! key = "acme/production.pem"
+//! kid = "7c3cbd4a-6d1c-4d4a-a1c1-52d0c6e4b6e1"
+//! alg = "ES512"
+//! url = "https://api.truelayer.com"
+//! signing = "legacy"
+//! ```
+//!
+//! Relative paths are relative to the directory of the configuration file.
+use crate::http::Url;
+use anyhow::Context;
+use serde::{Deserialize, Deserializer};
+use serde_json::{Map, Value};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tlsign::{Algorithm, KeyFormat};
+use uuid::Uuid;
+
+/// The environment variable holding the path of the configuration file.
+pub const CONFIG_ENV: &str = "TLSIGN_CONFIG";
+
+/// The configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The profile used when none is selected.
+    default_profile: Option<String>,
+    /// The profiles, only parsed when selected.
+    #[serde(default)]
+    profiles: BTreeMap<String, Value>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+/// The defaults of the command line options when signing for a given environment.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub key: Option<PathBuf>,
+    #[serde(default, deserialize_with = "from_str")]
+    pub key_format: Option<KeyFormat>,
+    pub passphrase_file: Option<PathBuf>,
+    #[serde(default, deserialize_with = "from_str")]
+    pub kid: Option<Uuid>,
+    /// The certificate uploaded in TrueLayer's Console for `kid`.
+    pub cert: Option<PathBuf>,
+    #[serde(default, deserialize_with = "from_str")]
+    pub alg: Option<Algorithm>,
+    /// The base URL requests are sent to, e.g. `https://api.truelayer-sandbox.com`.
+    #[serde(default, deserialize_with = "from_str")]
+    pub url: Option<Url>,
+    pub signing: Option<Signing>,
+}
+
+/// How requests are signed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Signing {
+    /// Request signing v2, as the `Tl-Signature` header.
+    V2,
+    /// The body alone, as the `X-TL-Signature` header of the Payouts/Paydirect API.
+    Legacy,
+}
+
+impl Config {
+    /// The path of the configuration file: `$TLSIGN_CONFIG`, otherwise `tlsign/config.toml` in
+    /// `$XDG_CONFIG_HOME` or `~/.config`.
+    pub fn path() -> Option<PathBuf> {
+        if let Some(path) = std::env::var_os(CONFIG_ENV) {
+            return Some(path.into());
+        }
+        let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(config_home) if !config_home.is_empty() => PathBuf::from(config_home),
+            _ => home_dir()?.join(".config"),
+        };
+        Some(config_home.join("tlsign").join("config.toml"))
+    }
+
+    /// Read the configuration file, if it exists.
+    pub fn load() -> anyhow::Result<Config> {
+        let path = match Config::path() {
+            Some(path) => path,
+            None => return Ok(Config::default()),
+        };
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Config {
+                    path,
+                    ..Config::default()
+                })
+            }
+            Err(error) => {
+                return Err(error).with_context(|| format!("Failed to read {}.", path.display()))
+            }
+        };
+        let mut config: Config = parse_toml(&text)
+            .and_then(|value| Ok(serde_json::from_value(value)?))
+            .with_context(|| format!("Invalid configuration file {}.", path.display()))?;
+        config.path = path;
+        Ok(config)
+    }
+
+    /// The profile called `name`, otherwise the default one if set, otherwise an empty profile.
+    pub fn profile(&self, name: Option<&str>) -> anyhow::Result<Profile> {
+        match name.or(self.default_profile.as_deref()) {
+            Some(name) => {
+                let profile = self.profiles.get(name).ok_or_else(|| {
+                    anyhow::anyhow!("There is no profile `{}` in {}.", name, self.path.display())
+                })?;
+                let mut profile: Profile =
+                    serde_json::from_value(profile.clone()).with_context(|| {
+                        format!("Invalid profile `{}` in {}.", name, self.path.display())
+                    })?;
+                let dir = self.path.parent().unwrap_or_else(|| Path::new(""));
+                for path in profile
+                    .key
+                    .iter_mut()
+                    .chain(profile.passphrase_file.iter_mut())
+                    .chain(profile.cert.iter_mut())
+                {
+                    *path = resolve(dir, path);
+                }
+                Ok(profile)
+            }
+            None => Ok(Profile::default()),
+        }
+    }
+}
+
+fn home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Expand a leading `~` to the home directory, and make a relative path relative to `dir`.
+fn resolve(dir: &Path, path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => dir.join(path),
+    }
+}
+
+/// Deserialize an optional string using the `FromStr` implementation of the type.
+fn from_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(s) => s
+            .parse()
+            .map(Some)
+            .map_err(|error| serde::de::Error::custom(format!("`{}`: {}", s, error))),
+        None => Ok(None),
+    }
+}
+
+/// Parse the subset of TOML needed by the configuration file: tables, and keys holding strings,
+/// integers or booleans.
+fn parse_toml(text: &str) -> anyhow::Result<Value> {
+    let mut root = Map::new();
+    let mut table = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        parse_line(&mut root, &mut table, line)
+            .with_context(|| format!("Invalid TOML on line {}.", i + 1))?;
+    }
+    Ok(Value::Object(root))
+}
+
+/// Parse a line, `table` being the keys of the table the previous lines are in.
+fn parse_line(
+    root: &mut Map<String, Value>,
+    table: &mut Vec<String>,
+    line: &str,
+) -> anyhow::Result<()> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(());
+    }
+
+    if let Some(rest) = line.strip_prefix('[') {
+        if rest.starts_with('[') {
+            return Err(anyhow::anyhow!("Arrays of tables are not supported."));
+        }
+        let (keys, rest) = parse_keys(rest)?;
+        let rest = rest
+            .strip_prefix(']')
+            .ok_or_else(|| anyhow::anyhow!("Expected `]` after the table name."))?;
+        expect_end(rest)?;
+        table_mut(root, &keys)?;
+        *table = keys;
+        return Ok(());
+    }
+
+    let (mut keys, rest) = parse_keys(line)?;
+    let rest = rest
+        .strip_prefix('=')
+        .ok_or_else(|| anyhow::anyhow!("Expected `=` after the key."))?;
+    let (value, rest) = parse_value(rest.trim_start())?;
+    expect_end(rest)?;
+    let key = keys.pop().unwrap_or_default();
+    let path: Vec<_> = table.iter().cloned().chain(keys).collect();
+    let table = table_mut(root, &path)?;
+    if table.contains_key(&key) {
+        return Err(anyhow::anyhow!("Duplicate key `{}`.", key));
+    }
+    table.insert(key, value);
+    Ok(())
+}
+
+/// Parse a bare, quoted or dotted key, returning what follows it.
+fn parse_keys(mut s: &str) -> anyhow::Result<(Vec<String>, &str)> {
+    let mut keys = Vec::new();
+    loop {
+        s = s.trim_start();
+        let (key, rest) = if s.starts_with(&['"', '\''][..]) {
+            parse_string(s)?
+        } else {
+            let end = s
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+                .unwrap_or(s.len());
+            if end == 0 {
+                return Err(anyhow::anyhow!("Expected a key."));
+            }
+            (s[..end].to_owned(), &s[end..])
+        };
+        keys.push(key);
+        s = rest.trim_start();
+        match s.strip_prefix('.') {
+            Some(rest) => s = rest,
+            None => return Ok((keys, s)),
+        }
+    }
+}
+
+/// Parse a string, integer or boolean value, returning what follows it.
+fn parse_value(s: &str) -> anyhow::Result<(Value, &str)> {
+    if s.starts_with(&['"', '\''][..]) {
+        let (string, rest) = parse_string(s)?;
+        return Ok((Value::String(string), rest));
+    }
+    let end = s
+        .find(|c: char| c.is_whitespace() || c == '#')
+        .unwrap_or(s.len());
+    let (token, rest) = s.split_at(end);
+    let value = match token {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        _ => token
+            .replace('_', "")
+            .parse::<i64>()
+            .map(Value::from)
+            .map_err(|_| {
+                anyhow::anyhow!(
+                    "Unsupported value `{}`, expected a string, an integer or a boolean.",
+                    token
+                )
+            })?,
+    };
+    Ok((value, rest))
+}
+
+/// Parse a basic (`"..."`) or literal (`'...'`) string, returning what follows it.
+fn parse_string(s: &str) -> anyhow::Result<(String, &str)> {
+    let mut chars = s.char_indices();
+    let quote = chars.next().map(|(_, c)| c).unwrap_or_default();
+    let mut string = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            _ if c == quote => return Ok((string, &s[i + 1..])),
+            '\\' if quote == '"' => {
+                let escaped = match chars.next().map(|(_, c)| c) {
+                    Some('b') => '\u{8}',
+                    Some('t') => '\t',
+                    Some('n') => '\n',
+                    Some('f') => '\u{c}',
+                    Some('r') => '\r',
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some(u @ 'u') | Some(u @ 'U') => {
+                        let len = if u == 'u' { 4 } else { 8 };
+                        let hex: String = chars.by_ref().take(len).map(|(_, c)| c).collect();
+                        u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .filter(|_| hex.len() == len)
+                            .and_then(std::char::from_u32)
+                            .ok_or_else(|| anyhow::anyhow!("Invalid escape `\\{}{}`.", u, hex))?
+                    }
+                    Some(c) => return Err(anyhow::anyhow!("Invalid escape `\\{}`.", c)),
+                    None => break,
+                };
+                string.push(escaped);
+            }
+            _ => string.push(c),
+        }
+    }
+    Err(anyhow::anyhow!("Unterminated string."))
+}
+
+/// Check that nothing but a comment follows a value or table name.
+fn expect_end(rest: &str) -> anyhow::Result<()> {
+    let rest = rest.trim_start();
+    if rest.is_empty() || rest.starts_with('#') {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("Unexpected `{}`.", rest))
+    }
+}
+
+/// The table at `keys`, created if missing.
+fn table_mut<'a>(
+    root: &'a mut Map<String, Value>,
+    keys: &[String],
+) -> anyhow::Result<&'a mut Map<String, Value>> {
+    let mut table = root;
+    for key in keys {
+        table = table
+            .entry(key.clone())
+            .or_insert_with(|| Value::Object(Map::new()))
+            .as_object_mut()
+            .ok_or_else(|| anyhow::anyhow!("`{}` is not a table.", key))?;
+    }
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn config(text: &str) -> Config {
+        let mut config: Config = serde_json::from_value(parse_toml(text).unwrap()).unwrap();
+        config.path = PathBuf::from("/etc/tlsign/config.toml");
+        config
+    }
+
+    #[test]
+    fn tables_and_values() {
+        let value = parse_toml(
+            r#"
+# A comment
+top = "level" # A trailing comment
+[a]
+string = 'literal \n'
+number = 1_000
+negative = -5
+flag = true
+[a.b]
+other = false
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "top": "level",
+                "a": {
+                    "string": "literal \\n",
+                    "number": 1000,
+                    "negative": -5,
+                    "flag": true,
+                    "b": {"other": false},
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn string_escapes() {
+        let value =
+            parse_toml(r#"s = "tab\t quote\" backslash\\ newline\n é \U0001F600 # not a comment""#)
+                .unwrap();
+        assert_eq!(
+            value["s"],
+            "tab\t quote\" backslash\\ newline\n \u{e9} \u{1f600} # not a comment"
+        );
+        for invalid in &[
+            r#"s = "\x""#,
+            r#"s = "\u12""#,
+            r#"s = "\uD800""#,
+            r#"s = "\U00110000""#,
+            r#"s = "unterminated"#,
+        ] {
+            assert!(parse_toml(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn dotted_and_quoted_keys() {
+        let value = parse_toml(
+            r#"
+a.b = 1
+"quoted key" = 2
+[profiles."acme.production"]
+'literal'.c = 3
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "a": {"b": 1},
+                "quoted key": 2,
+                "profiles": {"acme.production": {"literal": {"c": 3}}},
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_documents() {
+        for invalid in &[
+            "a = 1\na = 2",
+            "[t]\na = 1\n[t]\na = 2",
+            "a = 1\na.b = 2",
+            "[[array]]",
+            "a = [1, 2]",
+            "a = 1 b",
+            "[t",
+            "= 1",
+            "a",
+        ] {
+            assert!(parse_toml(invalid).is_err(), "{}", invalid);
+        }
+        let error = parse_toml("a = 1\n\nb = nope").unwrap_err();
+        assert_eq!(error.to_string(), "Invalid TOML on line 3.");
+    }
+
+    #[test]
+    fn profiles() {
+        let config = config(
+            r#"
+default_profile = "sandbox"
+[profiles.sandbox]
+key = "keys/sandbox.pem"
+passphrase_file = "/run/secrets/passphrase"
+kid = "45fc75cf-5649-4134-84b3-192c2c78e990"
+url = "https://api.truelayer-sandbox.com"
+[profiles.production]
+key = "~/keys/production.pem"
+cert = "production.crt"
+alg = "ES512"
+signing = "legacy"
+"#,
+        );
+        let sandbox = config.profile(None).unwrap();
+        assert_eq!(
+            sandbox.key,
+            Some(PathBuf::from("/etc/tlsign/keys/sandbox.pem"))
+        );
+        assert_eq!(
+            sandbox.passphrase_file,
+            Some(PathBuf::from("/run/secrets/passphrase"))
+        );
+        assert_eq!(
+            sandbox.kid.map(|kid| kid.to_string()).as_deref(),
+            Some("45fc75cf-5649-4134-84b3-192c2c78e990")
+        );
+        assert_eq!(sandbox.signing, None);
+
+        let production = config.profile(Some("production")).unwrap();
+        if let Some(home) = home_dir() {
+            assert_eq!(production.key, Some(home.join("keys/production.pem")));
+        }
+        assert_eq!(
+            production.cert,
+            Some(PathBuf::from("/etc/tlsign/production.crt"))
+        );
+        assert_eq!(production.alg, Some(Algorithm::ES512));
+        assert_eq!(production.signing, Some(Signing::Legacy));
+
+        assert!(config.profile(Some("missing")).is_err());
+        assert!(Config::default().profile(None).unwrap().key.is_none());
+    }
+
+    #[test]
+    fn invalid_profiles() {
+        let config = config(
+            r#"
+[profiles.typo]
+keys = "k.pem"
+[profiles.kid]
+kid = "not a uuid"
+[profiles.signing]
+signing = "v3"
+"#,
+        );
+        for name in &["typo", "kid", "signing"] {
+            let error = config.profile(Some(name)).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!("Invalid profile `{}` in /etc/tlsign/config.toml.", name)
+            );
+        }
+        // Only the selected profile is parsed
+        assert!(config.profile(None).is_ok());
+        assert!(serde_json::from_value::<Config>(parse_toml("default = \"a\"").unwrap()).is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use crate::{Algorithm, SigningKey};
+use openssl::{
+    bn::{BigNum, BigNumContext, BigNumRef},
+    ec::{EcKey, EcPoint},
+    hash::MessageDigest,
+    pkey::{PKey, Private, Public},
+    sign::Signer,
+};
+
+/// A private key in memory signing using deterministic ECDSA, as described in RFC6979: the nonce
+/// is derived from the key and the payload rather than random, so that signing the same payload
+/// twice gives the same signature, e.g. to compare the output of an integration to golden files.
+pub struct DeterministicKey {
+    key: EcKey<Private>,
+}
+
+impl DeterministicKey {
+    pub fn new(key: EcKey<Private>) -> Self {
+        DeterministicKey { key }
+    }
+}
+
+impl SigningKey for DeterministicKey {
+    fn alg(&self) -> Result<Algorithm, anyhow::Error> {
+        SigningKey::alg(&self.key)
+    }
+
+    fn public_key(&self) -> Result<EcKey<Public>, anyhow::Error> {
+        SigningKey::public_key(&self.key)
+    }
+
+    fn sign(&self, payload: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        let alg = SigningKey::alg(&self.key)?;
+        let group = self.key.group();
+        let mut ctx = BigNumContext::new()?;
+        let mut q = BigNum::new()?;
+        group.order(&mut q, &mut ctx)?;
+        let hash = openssl::hash::hash(alg.digest(), payload)?;
+        let e = bits2int(&hash, &q)?;
+
+        let d = self.key.private_key();
+        let mut nonces = Nonces::new(alg.digest(), d, &hash, &q)?;
+        let mut q_minus_2 = q.to_owned()?;
+        q_minus_2.sub_word(2)?;
+        loop {
+            let mut k = nonces.next()?;
+            // Selects the constant-time modular exponentiation below
+            k.set_const_time();
+            let mut point = EcPoint::new(group)?;
+            point.mul_generator(group, &k, &ctx)?;
+            let mut x = BigNum::new()?;
+            let mut y = BigNum::new()?;
+            point.affine_coordinates_gfp(group, &mut x, &mut y, &mut ctx)?;
+            let mut r = BigNum::new()?;
+            r.nnmod(&x, &q, &mut ctx)?;
+
+            // s = k^-1 (e + r d) mod q, computed as OpenSSL's own ECDSA signing does, as `mod_mul`
+            // and `mod_add` are not constant-time: the private key only enters the arithmetic
+            // multiplied by a random blinding factor b, as s = k^-1 (b e + b r d) b^-1 mod q, and
+            // k^-1 = k^(q - 2) mod q uses a constant-time exponentiation
+            let b = blinding_factor(&q)?;
+            let mut bd = BigNum::new()?;
+            bd.mod_mul(&b, d, &q, &mut ctx)?;
+            let mut brd = BigNum::new()?;
+            brd.mod_mul(&r, &bd, &q, &mut ctx)?;
+            let mut be = BigNum::new()?;
+            be.mod_mul(&b, &e, &q, &mut ctx)?;
+            let mut blinded_sum = BigNum::new()?;
+            blinded_sum.mod_add(&be, &brd, &q, &mut ctx)?;
+            let mut k_inverse = BigNum::new()?;
+            k_inverse.mod_exp(&k, &q_minus_2, &q, &mut ctx)?;
+            let mut blinded_s = BigNum::new()?;
+            blinded_s.mod_mul(&k_inverse, &blinded_sum, &q, &mut ctx)?;
+            let mut b_inverse = BigNum::new()?;
+            b_inverse.mod_inverse(&b, &q, &mut ctx)?;
+            let mut s = BigNum::new()?;
+            s.mod_mul(&blinded_s, &b_inverse, &q, &mut ctx)?;
+
+            // Negligibly likely, but the nonce must then be replaced as for an out of range one
+            if r.num_bits() == 0 || s.num_bits() == 0 {
+                continue;
+            }
+            let len = alg.coordinate_len() as i32;
+            return Ok([r.to_vec_padded(len)?, s.to_vec_padded(len)?].concat());
+        }
+    }
+}
+
+/// The candidate nonces of section 3.2 of RFC6979, drawn from an HMAC_DRBG seeded with the private
+/// key and the hash of the payload.
+struct Nonces<'a> {
+    digest: MessageDigest,
+    q: &'a BigNumRef,
+    k: Vec<u8>,
+    v: Vec<u8>,
+    first: bool,
+}
+
+impl<'a> Nonces<'a> {
+    fn new(
+        digest: MessageDigest,
+        x: &BigNumRef,
+        hash: &[u8],
+        q: &'a BigNumRef,
+    ) -> Result<Self, anyhow::Error> {
+        let rlen = (q.num_bits() + 7) / 8;
+        let x = x.to_vec_padded(rlen)?;
+        let mut h1 = bits2int(hash, q)?;
+        if h1.ucmp(q) != std::cmp::Ordering::Less {
+            let reduced = &h1 - q;
+            h1 = reduced;
+        }
+        let h1 = h1.to_vec_padded(rlen)?;
+
+        let mut nonces = Nonces {
+            digest,
+            q,
+            k: vec![0; digest.size()],
+            v: vec![1; digest.size()],
+            first: true,
+        };
+        for separator in &[0, 1] {
+            nonces.k = nonces.hmac(&[&nonces.v, &[*separator], &x, &h1])?;
+            nonces.v = nonces.hmac(&[&nonces.v])?;
+        }
+        Ok(nonces)
+    }
+
+    /// The next nonce in `[1, q - 1]`.
+    fn next(&mut self) -> Result<BigNum, anyhow::Error> {
+        loop {
+            if !self.first {
+                self.k = self.hmac(&[&self.v, &[0]])?;
+                self.v = self.hmac(&[&self.v])?;
+            }
+            self.first = false;
+
+            let mut t = Vec::new();
+            while t.len() * 8 < self.q.num_bits() as usize {
+                self.v = self.hmac(&[&self.v])?;
+                t.extend_from_slice(&self.v);
+            }
+            let k = bits2int(&t, self.q)?;
+            if k.num_bits() > 0 && k.ucmp(self.q) == std::cmp::Ordering::Less {
+                return Ok(k);
+            }
+        }
+    }
+
+    fn hmac(&self, data: &[&[u8]]) -> Result<Vec<u8>, anyhow::Error> {
+        let key = PKey::hmac(&self.k)?;
+        let mut signer = Signer::new(self.digest, &key)?;
+        for data in data {
+            signer.update(data)?;
+        }
+        Ok(signer.sign_to_vec()?)
+    }
+}
+
+/// A random blinding factor in `[1, q - 1]`. It cancels out of the signature, which stays
+/// deterministic.
+fn blinding_factor(q: &BigNumRef) -> Result<BigNum, anyhow::Error> {
+    let mut b = BigNum::new()?;
+    loop {
+        q.rand_range(&mut b)?;
+        if b.num_bits() > 0 {
+            return Ok(b);
+        }
+    }
+}
+
+/// The integer made of the leftmost bits of `bits`, as many as in the order `q` of the curve.
+fn bits2int(bits: &[u8], q: &BigNumRef) -> Result<BigNum, anyhow::Error> {
+    let int = BigNum::from_slice(bits)?;
+    let excess = bits.len() as i32 * 8 - q.num_bits();
+    if excess <= 0 {
+        return Ok(int);
+    }
+    let mut shifted = BigNum::new()?;
+    shifted.rshift(&int, excess)?;
+    Ok(shifted)
+}
@@ -0,0 +1,572 @@
+//! A minimal HTTP/1.1 client, enough to send a signed request and read the response, and the
+//! server side needed to proxy requests.
+use anyhow::Context;
+use openssl::ssl::{SslConnector, SslMethod};
+use std::{
+    fmt,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    str::FromStr,
+    time::Duration,
+};
+use tlsign::Header;
+
+/// How long to wait for the server before giving up.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The largest request body read, well above that of any API request, so that a client cannot
+/// exhaust the memory of the proxy.
+pub const MAX_REQUEST_BODY: u64 = 10 * 1024 * 1024;
+
+/// The error reading a body longer than allowed, to be answered with `413 Payload Too Large`.
+#[derive(Debug)]
+pub struct BodyTooLarge {
+    pub limit: u64,
+}
+
+impl fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The body is larger than {} bytes.", self.limit)
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+/// An `http` or `https` URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Url {
+    tls: bool,
+    host: String,
+    port: u16,
+    /// The path, including the query string if any.
+    target: String,
+}
+
+impl Url {
+    /// The path, without the query string, as signed by request signing v2.
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or("/")
+    }
+
+    /// The URL of `target`, e.g. `/v3/payments?a=b`, relative to the path of this URL.
+    pub fn join(&self, target: &str) -> Url {
+        let base = self.target.split('?').next().unwrap_or_default();
+        Url {
+            target: format!("{}{}", base.trim_end_matches('/'), target),
+            ..self.clone()
+        }
+    }
+
+    /// The `Host` header value, omitting the port when it is the default one.
+    fn authority(&self) -> String {
+        match (self.tls, self.port) {
+            (true, 443) | (false, 80) => self.host.clone(),
+            _ => format!("{}:{}", self.host, self.port),
+        }
+    }
+}
+
+impl FromStr for Url {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (tls, rest) = if let Some(rest) = s.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = s.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(anyhow::anyhow!(
+                "The URL `{}` must start with `https://` or `http://`.",
+                s
+            ));
+        };
+        let (authority, target) = match rest.find(&['/', '?'][..]) {
+            Some(i) if rest[i..].starts_with('?') => (&rest[..i], format!("/{}", &rest[i..])),
+            Some(i) => (&rest[..i], rest[i..].to_owned()),
+            None => (rest, "/".to_owned()),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .with_context(|| format!("Invalid port in the URL `{}`.", s))?,
+            ),
+            None => (authority, if tls { 443 } else { 80 }),
+        };
+        if host.is_empty() {
+            return Err(anyhow::anyhow!("The URL `{}` is missing a host.", s));
+        }
+        Ok(Url {
+            tls,
+            host: host.to_owned(),
+            port,
+            target,
+        })
+    }
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.tls { "https" } else { "http" };
+        write!(f, "{}://{}{}", scheme, self.authority(), self.target)
+    }
+}
+
+/// An HTTP response, with its body fully read.
+pub struct Response {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<Header>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/// An HTTP request, with its body fully read.
+pub struct Request {
+    pub method: String,
+    /// The path, including the query string if any.
+    pub target: String,
+    pub headers: Vec<Header>,
+    pub body: Vec<u8>,
+}
+
+/// Read a whole request, answering `Expect: 100-continue` so that the client sends its body.
+/// Fails with `BodyTooLarge` if the body is longer than `MAX_REQUEST_BODY`.
+pub fn read_request(stream: &mut (impl Read + Write)) -> anyhow::Result<Request> {
+    let mut reader = BufReader::new(stream);
+    let (request_line, headers) = read_head(&mut reader)?;
+    let mut request_line = request_line.split(' ');
+    let (method, target) = match (request_line.next(), request_line.next()) {
+        (Some(method), Some(target)) if target.starts_with('/') => (method, target),
+        _ => return Err(anyhow::anyhow!("The request has an invalid request line.")),
+    };
+
+    if header_value(&headers, "Expect")
+        .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"))
+    {
+        reader
+            .get_mut()
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+    }
+    // Unlike responses, requests without a length have no body
+    let body = if header_value(&headers, "Transfer-Encoding").is_some()
+        || header_value(&headers, "Content-Length").is_some()
+    {
+        read_body(&mut reader, &headers, MAX_REQUEST_BODY)?
+    } else {
+        Vec::new()
+    };
+    Ok(Request {
+        method: method.to_owned(),
+        target: target.to_owned(),
+        headers,
+        body,
+    })
+}
+
+/// Write a response, closing the connection after it. The `Content-Length` and `Connection`
+/// headers are set according to the body, replacing those of `response`.
+pub fn write_response(writer: &mut impl Write, response: &Response) -> anyhow::Result<()> {
+    let mut head = format!("HTTP/1.1 {} {}\r\n", response.status, response.reason);
+    for header in &response.headers {
+        if !is_hop_by_hop(header.name()) {
+            head.push_str(&format!("{}: {}\r\n", header.name(), header.value()));
+        }
+    }
+    head.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+    head.push_str("Connection: close\r\n\r\n");
+    writer.write_all(head.as_bytes())?;
+    writer.write_all(&response.body)?;
+    Ok(writer.flush()?)
+}
+
+/// Whether a header only applies to a single connection, as opposed to being forwarded by a
+/// proxy. The length headers are included, as the proxy reframes the body it forwards.
+pub fn is_hop_by_hop(name: &str) -> bool {
+    [
+        "Connection",
+        "Keep-Alive",
+        "Proxy-Authenticate",
+        "Proxy-Authorization",
+        "Proxy-Connection",
+        "TE",
+        "Trailer",
+        "Transfer-Encoding",
+        "Upgrade",
+        "Content-Length",
+        "Expect",
+        "Host",
+    ]
+    .iter()
+    .any(|hop_by_hop| hop_by_hop.eq_ignore_ascii_case(name))
+}
+
+/// Anything a request can be written to and a response read from.
+trait Stream: Read + Write {}
+
+impl<S: Read + Write> Stream for S {}
+
+/// Send a request and read the whole response. `Host`, `Content-Length` and `Connection`
+/// headers are added to `headers`.
+pub fn send(url: &Url, method: &str, headers: &[Header], body: &[u8]) -> anyhow::Result<Response> {
+    let tcp = TcpStream::connect((url.host.as_str(), url.port))
+        .with_context(|| format!("Failed to connect to {}.", url.authority()))?;
+    tcp.set_read_timeout(Some(TIMEOUT))?;
+    tcp.set_write_timeout(Some(TIMEOUT))?;
+    let mut stream: Box<dyn Stream> = if url.tls {
+        let connector = SslConnector::builder(SslMethod::tls())?.build();
+        Box::new(
+            connector
+                .connect(&url.host, tcp)
+                .with_context(|| format!("TLS handshake with {} failed.", url.authority()))?,
+        )
+    } else {
+        Box::new(tcp)
+    };
+
+    let mut request = format!("{} {} HTTP/1.1\r\n", method, url.target);
+    request.push_str(&format!("Host: {}\r\n", url.authority()));
+    for header in headers {
+        request.push_str(&format!("{}: {}\r\n", header.name(), header.value()));
+    }
+    request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    request.push_str("Connection: close\r\n\r\n");
+    stream
+        .write_all(request.as_bytes())
+        .and_then(|_| stream.write_all(body))
+        .and_then(|_| stream.flush())
+        .context("Failed to send the request.")?;
+
+    read_response(&mut BufReader::new(stream), method)
+}
+
+fn read_response(reader: &mut impl BufRead, method: &str) -> anyhow::Result<Response> {
+    let (status_line, headers) = read_head(reader)?;
+    let mut status_line = status_line.splitn(3, ' ');
+    let status = status_line
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .context("The response has an invalid status line.")?;
+    let reason = status_line.next().unwrap_or_default().to_owned();
+
+    // These responses never have a body, whatever their headers say
+    let body = if method.eq_ignore_ascii_case("HEAD") || status == 204 || status == 304 {
+        Vec::new()
+    } else {
+        read_body(reader, &headers, u64::MAX)?
+    };
+    Ok(Response {
+        status,
+        reason,
+        headers,
+        body,
+    })
+}
+
+/// Read the start line and the headers of a message.
+fn read_head(reader: &mut impl BufRead) -> anyhow::Result<(String, Vec<Header>)> {
+    let start_line = read_line(reader)?;
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            return Ok((start_line, headers));
+        }
+        headers.push(
+            line.parse::<Header>()
+                .with_context(|| format!("Invalid header `{}`.", line))?,
+        );
+    }
+}
+
+/// Read the body of a message, as delimited by its headers or by the end of the connection.
+/// The body is read as it arrives rather than allocated upfront from the announced length, and
+/// reading fails with `BodyTooLarge` past `limit` bytes.
+fn read_body(reader: &mut impl BufRead, headers: &[Header], limit: u64) -> anyhow::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    if let Some(encoding) = header_value(headers, "Transfer-Encoding") {
+        if !encoding.eq_ignore_ascii_case("chunked") {
+            return Err(anyhow::anyhow!(
+                "Unsupported transfer encoding `{}`.",
+                encoding
+            ));
+        }
+        loop {
+            let size = read_line(reader)?;
+            let size = size.split(';').next().unwrap_or_default().trim();
+            let size = u64::from_str_radix(size, 16)
+                .with_context(|| format!("Invalid chunk size `{}`.", size))?;
+            if size == 0 {
+                // Skip the trailers
+                while !read_line(reader)?.is_empty() {}
+                return Ok(body);
+            }
+            if size > limit - body.len() as u64 {
+                return Err(BodyTooLarge { limit }.into());
+            }
+            read_exactly(reader, size, &mut body)?;
+            read_line(reader)?;
+        }
+    } else if let Some(length) = header_value(headers, "Content-Length") {
+        let length = length
+            .parse()
+            .with_context(|| format!("Invalid content length `{}`.", length))?;
+        if length > limit {
+            return Err(BodyTooLarge { limit }.into());
+        }
+        read_exactly(reader, length, &mut body)?;
+    } else {
+        // One more byte than allowed tells a body at the limit from a longer one
+        reader
+            .take(limit.saturating_add(1))
+            .read_to_end(&mut body)?;
+        if body.len() as u64 > limit {
+            return Err(BodyTooLarge { limit }.into());
+        }
+    }
+    Ok(body)
+}
+
+/// Append `length` bytes to `body`, failing if the connection is closed before.
+fn read_exactly(reader: &mut impl Read, length: u64, body: &mut Vec<u8>) -> anyhow::Result<()> {
+    let read = reader.take(length).read_to_end(body)?;
+    if (read as u64) < length {
+        return Err(anyhow::anyhow!(
+            "The connection was closed before the end of the body."
+        ));
+    }
+    Ok(())
+}
+
+/// Read a line, without its line ending.
+fn read_line(reader: &mut impl BufRead) -> anyhow::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(anyhow::anyhow!("The connection was closed unexpectedly."));
+    }
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+/// The value of the first header named `name`, ignoring case.
+pub fn header_value<'a>(headers: &'a [Header], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|header| header.name().eq_ignore_ascii_case(name))
+        .map(Header::value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Cursor, net::TcpListener, thread};
+    use tlsign::{generate_key, Algorithm, DetachedJws, Signer, SigningKey};
+
+    #[test]
+    fn parse_urls() {
+        let url: Url = "https://api.truelayer.com/v3/payments?a=b".parse().unwrap();
+        assert_eq!(url.authority(), "api.truelayer.com");
+        assert_eq!(url.port, 443);
+        assert_eq!(url.path(), "/v3/payments");
+        assert_eq!(url.to_string(), "https://api.truelayer.com/v3/payments?a=b");
+
+        let url: Url = "http://127.0.0.1:8080?a=b".parse().unwrap();
+        assert_eq!(url.authority(), "127.0.0.1:8080");
+        assert_eq!(url.path(), "/");
+        assert_eq!(url.target, "/?a=b");
+        assert_eq!(
+            "http://localhost".parse::<Url>().unwrap().to_string(),
+            "http://localhost/"
+        );
+
+        for invalid in &["api.truelayer.com", "ftp://a", "https://", "http://a:port/"] {
+            assert!(invalid.parse::<Url>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn join_urls() {
+        let base: Url = "https://api.truelayer.com/".parse().unwrap();
+        assert_eq!(
+            base.join("/v3/payments?a=b").to_string(),
+            "https://api.truelayer.com/v3/payments?a=b"
+        );
+        let base: Url = "http://localhost:8080/prefix/?ignored".parse().unwrap();
+        let url = base.join("/v3/payments");
+        assert_eq!(url.to_string(), "http://localhost:8080/prefix/v3/payments");
+        assert_eq!(url.path(), "/prefix/v3/payments");
+    }
+
+    #[test]
+    fn read_bodies() {
+        let chunked = [Header::new("Transfer-Encoding", "chunked")];
+        let body = read_body(
+            &mut Cursor::new(&b"4;ext=1\r\nWiki\r\n6\r\npedia \r\n0\r\nTrailer: x\r\n\r\n"[..]),
+            &chunked,
+            u64::MAX,
+        )
+        .unwrap();
+        assert_eq!(body, b"Wikipedia ");
+        assert!(read_body(&mut Cursor::new(&b"zz\r\n"[..]), &chunked, u64::MAX).is_err());
+        assert!(read_body(&mut Cursor::new(&b"4\r\nWi"[..]), &chunked, u64::MAX).is_err());
+
+        let length = [Header::new("Content-Length", "3")];
+        assert_eq!(
+            read_body(&mut Cursor::new(&b"abcdef"[..]), &length, u64::MAX).unwrap(),
+            b"abc"
+        );
+        assert!(read_body(&mut Cursor::new(&b"ab"[..]), &length, u64::MAX).is_err());
+        assert_eq!(
+            read_body(&mut Cursor::new(&b"until the end"[..]), &[], u64::MAX).unwrap(),
+            b"until the end"
+        );
+        assert!(read_body(
+            &mut Cursor::new(&b""[..]),
+            &[Header::new("Transfer-Encoding", "gzip")],
+            u64::MAX
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn limit_bodies() {
+        let too_large = |result: anyhow::Result<Vec<u8>>| {
+            result
+                .unwrap_err()
+                .downcast::<BodyTooLarge>()
+                .unwrap()
+                .limit
+        };
+        // The announced length is rejected before reading anything
+        let length = [Header::new("Content-Length", "99999999999999999")];
+        assert_eq!(
+            too_large(read_body(&mut Cursor::new(&b""[..]), &length, 4)),
+            4
+        );
+        let chunked = [Header::new("Transfer-Encoding", "chunked")];
+        assert_eq!(
+            too_large(read_body(
+                &mut Cursor::new(&b"3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n"[..]),
+                &chunked,
+                4
+            )),
+            4
+        );
+        assert_eq!(
+            too_large(read_body(&mut Cursor::new(&b"abcde"[..]), &[], 4)),
+            4
+        );
+        assert_eq!(
+            read_body(&mut Cursor::new(&b"abcd"[..]), &[], 4).unwrap(),
+            b"abcd"
+        );
+        assert_eq!(
+            read_body(
+                &mut Cursor::new(&b"4\r\nabcd\r\n0\r\n\r\n"[..]),
+                &chunked,
+                4
+            )
+            .unwrap(),
+            b"abcd"
+        );
+    }
+
+    #[test]
+    fn read_responses() {
+        let response = read_response(
+            &mut Cursor::new(&b"HTTP/1.1 404 Not Found\r\nContent-Length: 2\r\n\r\n{}"[..]),
+            "GET",
+        )
+        .unwrap();
+        assert_eq!(response.status, 404);
+        assert_eq!(response.reason, "Not Found");
+        assert_eq!(response.body, b"{}");
+        assert!(!response.is_success());
+
+        // No body, whatever the headers say
+        let head = b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n";
+        assert!(read_response(&mut Cursor::new(&head[..]), "HEAD")
+            .unwrap()
+            .body
+            .is_empty());
+        assert!(read_response(&mut Cursor::new(&b"garbage\r\n\r\n"[..]), "GET").is_err());
+    }
+
+    /// Send a signed request to a local stand-in for the API, answering with a chunked response.
+    #[test]
+    fn send_signed_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = read_request(&mut stream).unwrap();
+            stream
+                .write_all(
+                    b"HTTP/1.1 201 Created\r\nContent-Type: application/json\r\n\
+                      Transfer-Encoding: chunked\r\n\r\n6\r\n{\"id\":\r\n5\r\n\"abc\"\r\n1\r\n}\r\n0\r\n\r\n",
+                )
+                .unwrap();
+            request
+        });
+
+        let key = generate_key(Algorithm::ES512).unwrap();
+        let public_key = SigningKey::public_key(&key).unwrap();
+        let signer = Signer::new(key, "45fc75cf-5649-4134-84b3-192c2c78e990").unwrap();
+        let url: Url = format!("http://127.0.0.1:{}/v3/payments?a=b", port)
+            .parse()
+            .unwrap();
+        let body = br#"{"amount_in_minor":100}"#;
+        let signed_headers = [
+            Header::new("Idempotency-Key", "1"),
+            Header::new("X-Custom", "a"),
+        ];
+        let jws = signer
+            .sign_request("POST", url.path(), &signed_headers, body)
+            .unwrap();
+        let mut headers = signed_headers.to_vec();
+        headers.push(Header::new("Tl-Signature", jws.to_string()));
+
+        let response = send(&url, "POST", &headers, body).unwrap();
+        assert_eq!(response.status, 201);
+        assert_eq!(response.body, br#"{"id":"abc"}"#);
+
+        let request = server.join().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.target, "/v3/payments?a=b");
+        assert_eq!(
+            request
+                .headers
+                .iter()
+                .map(|header| format!("{}: {}", header.name(), header.value()))
+                .collect::<Vec<_>>(),
+            [
+                format!("Host: 127.0.0.1:{}", port),
+                "Idempotency-Key: 1".to_owned(),
+                "X-Custom: a".to_owned(),
+                format!("Tl-Signature: {}", jws),
+                format!("Content-Length: {}", body.len()),
+                "Connection: close".to_owned(),
+            ]
+        );
+        assert_eq!(request.body, body);
+
+        // The signature covers the request as received, without the query string
+        let received: DetachedJws = header_value(&request.headers, "Tl-Signature")
+            .unwrap()
+            .parse()
+            .unwrap();
+        received
+            .verify_request(
+                &request.method,
+                "/v3/payments",
+                &request.headers,
+                &request.body,
+                &public_key,
+            )
+            .unwrap();
+    }
+}
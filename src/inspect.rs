@@ -0,0 +1,363 @@
+//! Decoding a JWS and explaining what is wrong with it, if anything.
+use anyhow::Context;
+use openssl::ecdsa::EcdsaSig;
+use serde_json::Value;
+use std::fmt::Write;
+use tlsign::{base64_decode, Algorithm};
+use uuid::Uuid;
+
+/// Describe a compact or detached JWS, listing the problems found.
+pub fn inspect(jws: &str) -> anyhow::Result<String> {
+    let jws = jws.trim();
+    // Accept the whole header line, as copied from logs
+    let jws = ["Tl-Signature:", "X-TL-Signature:"]
+        .iter()
+        .find_map(|name| {
+            jws.get(..name.len())
+                .filter(|prefix| prefix.eq_ignore_ascii_case(name))
+                .map(|_| jws[name.len()..].trim_start())
+        })
+        .unwrap_or(jws);
+    let (header, payload, signature) = match jws.split('.').collect::<Vec<_>>().as_slice() {
+        [header, payload, signature] => (*header, *payload, *signature),
+        parts => {
+            return Err(anyhow::anyhow!(
+                "A JWS must be made of three `.` separated parts, found {}.",
+                parts.len()
+            ))
+        }
+    };
+
+    let mut out = String::new();
+    let mut warnings = Vec::new();
+
+    let header = decode("header", header, &mut warnings)
+        .context("Failed to base64url decode the header.")?;
+    let header: Value =
+        serde_json::from_slice(&header).context("The header is not a JSON object.")?;
+    writeln!(out, "Header:\n{}", serde_json::to_string_pretty(&header)?)?;
+    let alg = check_header(&header, &mut warnings);
+
+    // RFC7797: the payload is as is rather than base64url encoded
+    let unencoded = header.get("b64") == Some(&Value::Bool(false));
+    if payload.is_empty() {
+        writeln!(out, "Payload: detached")?;
+    } else if unencoded {
+        writeln!(
+            out,
+            "Payload (unencoded, {} bytes):\n{}",
+            payload.len(),
+            payload
+        )?;
+    } else {
+        match decode("payload", payload, &mut warnings) {
+            Ok(payload) => {
+                let text = match serde_json::from_slice::<Value>(&payload) {
+                    Ok(json) => serde_json::to_string_pretty(&json)?,
+                    Err(_) => String::from_utf8_lossy(&payload).into_owned(),
+                };
+                writeln!(out, "Payload ({} bytes):\n{}", payload.len(), text)?;
+            }
+            Err(error) => warnings.push(format!("The payload is not valid base64url: {}.", error)),
+        }
+    }
+
+    match decode("signature", signature, &mut warnings) {
+        Ok(signature) => {
+            write!(out, "Signature: {} bytes", signature.len())?;
+            if let Some(alg) = alg {
+                let expected = 2 * alg.coordinate_len();
+                if signature.len() == expected {
+                    write!(out, ", as expected for {}", alg)?;
+                } else if signature.first() == Some(&0x30) && EcdsaSig::from_der(&signature).is_ok()
+                {
+                    warnings.push(format!(
+                        "The signature is DER encoded, as output by `openssl dgst -sign`, but a JWS \
+                         holds the raw `r || s` concatenation: {} bytes for {}.",
+                        expected, alg
+                    ));
+                } else {
+                    warnings.push(format!(
+                        "The signature is {} bytes long, but {} signatures are {} bytes long.",
+                        signature.len(),
+                        alg,
+                        expected
+                    ));
+                }
+            }
+            writeln!(out)?;
+        }
+        Err(error) => warnings.push(format!("The signature is not valid base64url: {}.", error)),
+    }
+
+    if warnings.is_empty() {
+        write!(out, "\nNo problems found.")?;
+    } else {
+        write!(out, "\nWarnings:")?;
+        for warning in warnings {
+            write!(out, "\n- {}", warning)?;
+        }
+    }
+    Ok(out)
+}
+
+/// Decode base64url without padding, as JWS parts must be, warning about other base64 variants.
+fn decode(
+    name: &str,
+    part: &str,
+    warnings: &mut Vec<String>,
+) -> Result<Vec<u8>, base64::DecodeError> {
+    if part.contains(&['+', '/', '='][..]) {
+        warnings.push(format!(
+            "The {} is encoded as standard or padded base64, rather than base64url without \
+             padding.",
+            name
+        ));
+    }
+    base64_decode(
+        &part
+            .trim_end_matches('=')
+            .replace('+', "-")
+            .replace('/', "_"),
+    )
+}
+
+/// Check the protected header, returning its algorithm if supported.
+fn check_header(header: &Value, warnings: &mut Vec<String>) -> Option<Algorithm> {
+    let alg = match header.get("alg").and_then(Value::as_str) {
+        Some(alg) => match alg.parse::<Algorithm>() {
+            Ok(alg) => Some(alg),
+            Err(error) => {
+                warnings.push(error.to_string());
+                None
+            }
+        },
+        None => {
+            warnings.push("The header is missing `alg`.".to_owned());
+            None
+        }
+    };
+
+    match header.get("kid").and_then(Value::as_str) {
+        Some(kid) if kid.parse::<Uuid>().is_err() => warnings.push(format!(
+            "The `kid` {} is not a UUID, unlike the certificate ids of TrueLayer's Console.",
+            kid
+        )),
+        Some(_) => {}
+        None => warnings.push(
+            "The header is missing `kid`, the certificate id identifying the key to verify with."
+                .to_owned(),
+        ),
+    }
+
+    match header.get("tl_version") {
+        Some(Value::String(tl_version))
+            if tl_version == "2"
+                && !matches!(header.get("tl_headers"), Some(Value::String(_)) | None) =>
+        {
+            warnings.push("`tl_headers` must be a comma separated string.".to_owned())
+        }
+        Some(Value::String(tl_version)) if tl_version == "2" => {}
+        Some(tl_version) => warnings.push(format!(
+            "Unknown `tl_version` {}, request signing v2 uses \"2\".",
+            tl_version
+        )),
+        // Signatures of the payload alone, for the `X-TL-Signature` header, have no version
+        None if header.get("tl_headers").is_some() => {
+            warnings.push("`tl_headers` is set but `tl_version` is missing.".to_owned())
+        }
+        None => {}
+    }
+
+    let crit = header.get("crit").and_then(Value::as_array);
+    let b64_is_critical = crit.is_some_and(|crit| crit.contains(&Value::from("b64")));
+    match header.get("b64") {
+        Some(Value::Bool(false)) if !b64_is_critical => warnings.push(
+            "`\"b64\": false` must be listed in `crit`, as `\"crit\": [\"b64\"]`, otherwise \
+             verifiers ignoring it check the wrong payload."
+                .to_owned(),
+        ),
+        Some(Value::Bool(false)) => warnings.push(
+            "The payload is signed unencoded (RFC7797), which TrueLayer's APIs do not accept."
+                .to_owned(),
+        ),
+        Some(Value::Bool(true)) | None => {}
+        Some(b64) => warnings.push(format!("`b64` must be a boolean, found {}.", b64)),
+    }
+    if let Some(name) = crit
+        .into_iter()
+        .flatten()
+        .find(|name| name.as_str() != Some("b64"))
+    {
+        warnings.push(format!("Unsupported critical header parameter {}.", name));
+    }
+
+    alg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::bn::BigNum;
+    use serde_json::json;
+    use tlsign::{base64_encode, generate_key, get_jws, Signer};
+
+    const KID: &str = "45fc75cf-5649-4134-84b3-192c2c78e990";
+
+    /// A detached ES512 JWS with `header`, and its raw signature.
+    fn detached_jws(header: Value) -> (String, Vec<u8>) {
+        let key = generate_key(Algorithm::ES512).unwrap();
+        let jws = get_jws(&header, b"{}", &key).unwrap();
+        let parts: Vec<_> = jws.split('.').collect();
+        (
+            format!("{}..{}", parts[0], parts[2]),
+            base64_decode(parts[2]).unwrap(),
+        )
+    }
+
+    fn warnings(jws: &str) -> Vec<String> {
+        let out = inspect(jws).unwrap();
+        match out.split_once("\nWarnings:\n- ") {
+            Some((_, warnings)) => warnings.split("\n- ").map(str::to_owned).collect(),
+            None => {
+                assert!(out.ends_with("\nNo problems found."), "{}", out);
+                Vec::new()
+            }
+        }
+    }
+
+    #[test]
+    fn valid_signatures() {
+        let key = generate_key(Algorithm::ES512).unwrap();
+        let signer = Signer::new(key, KID).unwrap();
+        let jws = signer
+            .sign_request(
+                "POST",
+                "/v3/payments",
+                &["Idempotency-Key:1".parse().unwrap()],
+                b"{}",
+            )
+            .unwrap()
+            .to_string();
+        let out = inspect(&jws).unwrap();
+        assert!(out.contains("Payload: detached\n"), "{}", out);
+        assert!(
+            out.contains("Signature: 132 bytes, as expected for ES512\n"),
+            "{}",
+            out
+        );
+        assert!(warnings(&jws).is_empty());
+
+        // The whole header line, as copied from logs
+        assert!(warnings(&format!("Tl-Signature: {}", jws)).is_empty());
+        assert!(warnings(&format!("x-tl-signature:{}\n", jws)).is_empty());
+        assert!(inspect(&format!("Authorization: {}", jws)).is_err());
+    }
+
+    #[test]
+    fn missing_kid() {
+        let (jws, _) = detached_jws(json!({"alg": "ES512"}));
+        assert_eq!(
+            warnings(&jws),
+            ["The header is missing `kid`, the certificate id identifying the key to verify with."]
+        );
+        let (jws, _) = detached_jws(json!({"alg": "ES512", "kid": "my-key"}));
+        assert_eq!(
+            warnings(&jws),
+            ["The `kid` my-key is not a UUID, unlike the certificate ids of TrueLayer's Console."]
+        );
+    }
+
+    #[test]
+    fn unknown_tl_version() {
+        let (jws, _) = detached_jws(json!({"alg": "ES512", "kid": KID, "tl_version": "3"}));
+        assert_eq!(
+            warnings(&jws),
+            ["Unknown `tl_version` \"3\", request signing v2 uses \"2\"."]
+        );
+        let (jws, _) = detached_jws(json!({"alg": "ES512", "kid": KID, "tl_version": 2}));
+        assert_eq!(
+            warnings(&jws),
+            ["Unknown `tl_version` 2, request signing v2 uses \"2\"."]
+        );
+        let (jws, _) = detached_jws(json!({"alg": "ES512", "kid": KID, "tl_headers": ""}));
+        assert_eq!(
+            warnings(&jws),
+            ["`tl_headers` is set but `tl_version` is missing."]
+        );
+    }
+
+    #[test]
+    fn der_encoded_signature() {
+        let (jws, signature) = detached_jws(json!({"alg": "ES512", "kid": KID}));
+        let (r, s) = signature.split_at(66);
+        let der = EcdsaSig::from_private_components(
+            BigNum::from_slice(r).unwrap(),
+            BigNum::from_slice(s).unwrap(),
+        )
+        .unwrap()
+        .to_der()
+        .unwrap();
+        let header = jws.split('.').next().unwrap();
+        let jws = format!("{}..{}", header, base64_encode(&der));
+        assert_eq!(
+            warnings(&jws),
+            [
+                "The signature is DER encoded, as output by `openssl dgst -sign`, but a JWS holds \
+                 the raw `r || s` concatenation: 132 bytes for ES512."
+            ]
+        );
+    }
+
+    #[test]
+    fn wrong_signature_length() {
+        let (jws, signature) = detached_jws(json!({"alg": "ES512", "kid": KID}));
+        let header = jws.split('.').next().unwrap();
+        let jws = format!("{}..{}", header, base64_encode(&signature[..128]));
+        assert_eq!(
+            warnings(&jws),
+            ["The signature is 128 bytes long, but ES512 signatures are 132 bytes long."]
+        );
+    }
+
+    #[test]
+    fn standard_or_padded_base64() {
+        // Almost every signature has a `+` or a `/` in standard base64
+        let (header, signature, standard) = std::iter::repeat_with(|| {
+            let (jws, signature) = detached_jws(json!({"alg": "ES512", "kid": KID}));
+            let standard = base64::encode_config(&signature, base64::STANDARD);
+            (
+                jws.split('.').next().unwrap().to_owned(),
+                signature,
+                standard,
+            )
+        })
+        .find(|(_, _, standard)| standard.contains(&['+', '/'][..]))
+        .unwrap();
+        let out = inspect(&format!("{}..{}", header, standard)).unwrap();
+        assert!(
+            out.contains("Signature: 132 bytes, as expected for ES512\n"),
+            "{}",
+            out
+        );
+        assert_eq!(
+            warnings(&format!("{}..{}", header, standard)),
+            [
+                "The signature is encoded as standard or padded base64, rather than base64url \
+                 without padding."
+            ]
+        );
+        let padded = base64::encode_config(
+            format!(r#"{{"alg":"ES512","kid":"{}","tl_version":"2"}}"#, KID),
+            base64::URL_SAFE,
+        );
+        assert!(padded.ends_with('='));
+        assert_eq!(
+            warnings(&format!("{}..{}", padded, base64_encode(&signature))),
+            [
+                "The header is encoded as standard or padded base64, rather than base64url \
+                 without padding."
+            ]
+        );
+    }
+}
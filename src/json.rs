@@ -0,0 +1,331 @@
+use anyhow::Context;
+use serde::de::{self, Deserialize, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use std::{collections::HashSet, fmt};
+
+/// Canonicalize a JSON document as described in RFC8785, the JSON Canonicalization Scheme (JCS):
+/// no whitespace, object members sorted by the UTF-16 code units of their names, and numbers
+/// serialized as ECMAScript does, so that equal documents have the same bytes.
+pub fn canonicalize_json(json: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    let value: JcsValue = serde_json::from_slice(json).context("The payload is not valid JSON.")?;
+    let mut canonical = String::new();
+    value.write(&mut canonical)?;
+    Ok(canonical.into_bytes())
+}
+
+/// Remove the whitespace between the tokens of a JSON document, leaving them byte for byte as is.
+pub fn minify_json(json: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    serde_json::from_slice::<IgnoredAny>(json).context("The payload is not valid JSON.")?;
+    let mut minified = Vec::with_capacity(json.len());
+    let mut in_string = false;
+    let mut escaped = false;
+    for &byte in json {
+        if in_string {
+            in_string = escaped || byte != b'"';
+            escaped = !escaped && byte == b'\\';
+        } else if byte == b'"' {
+            in_string = true;
+        } else if matches!(byte, b' ' | b'\t' | b'\n' | b'\r') {
+            continue;
+        }
+        minified.push(byte);
+    }
+    Ok(minified)
+}
+
+/// A JSON value, keeping the members of objects in order to reject duplicate names, as required by
+/// I-JSON (RFC7493) which JCS builds upon.
+enum JcsValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<JcsValue>),
+    Object(Vec<(String, JcsValue)>),
+}
+
+impl JcsValue {
+    fn write(&self, out: &mut String) -> Result<(), anyhow::Error> {
+        match self {
+            JcsValue::Null => out.push_str("null"),
+            JcsValue::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+            JcsValue::Number(n) => out.push_str(&ecmascript_number(*n)?),
+            // The escaping of serde_json is the one of JCS: only `"`, `\` and control characters,
+            // using the short forms when they exist and lowercase hexadecimal otherwise
+            JcsValue::String(s) => out.push_str(&serde_json::to_string(s)?),
+            JcsValue::Array(values) => {
+                out.push('[');
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    value.write(out)?;
+                }
+                out.push(']');
+            }
+            JcsValue::Object(members) => {
+                let mut members: Vec<_> = members.iter().collect();
+                members.sort_by(|(a, _), (b, _)| a.encode_utf16().cmp(b.encode_utf16()));
+                out.push('{');
+                for (i, (name, value)) in members.into_iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    out.push_str(&serde_json::to_string(name)?);
+                    out.push(':');
+                    value.write(out)?;
+                }
+                out.push('}');
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Serialize a number as `Number.prototype.toString` does in ECMAScript, as JCS requires.
+fn ecmascript_number(n: f64) -> Result<String, anyhow::Error> {
+    if !n.is_finite() {
+        return Err(anyhow::anyhow!("{} cannot be represented in JSON.", n));
+    }
+    if n == 0.0 {
+        // Including -0
+        return Ok("0".to_owned());
+    }
+    // The shortest digits which parse back to `n`, e.g. `1.2345e3` for 1234.5
+    let scientific = format!("{:e}", n.abs());
+    let (mantissa, exponent) = scientific
+        .split_once('e')
+        .context("Unexpected scientific notation.")?;
+    let exponent = exponent.parse::<i32>()?;
+    let digits = break_tie(n.abs(), mantissa.replace('.', ""), exponent);
+    let k = digits.len() as i32;
+    // The position of the decimal point relative to the digits
+    let n_point = exponent + 1;
+
+    let mut out = String::new();
+    if n < 0.0 {
+        out.push('-');
+    }
+    if k <= n_point && n_point <= 21 {
+        out.push_str(&digits);
+        out.push_str(&"0".repeat((n_point - k) as usize));
+    } else if 0 < n_point && n_point <= 21 {
+        out.push_str(&digits[..n_point as usize]);
+        out.push('.');
+        out.push_str(&digits[n_point as usize..]);
+    } else if -6 < n_point && n_point <= 0 {
+        out.push_str("0.");
+        out.push_str(&"0".repeat(-n_point as usize));
+        out.push_str(&digits);
+    } else {
+        out.push_str(&digits[..1]);
+        if k > 1 {
+            out.push('.');
+            out.push_str(&digits[1..]);
+        }
+        out.push('e');
+        out.push(if n_point > 0 { '+' } else { '-' });
+        out.push_str(&(n_point - 1).abs().to_string());
+    }
+    Ok(out)
+}
+
+/// Pick the even digits when `n` is exactly halfway between two candidates as short as `digits`,
+/// as ECMAScript does, whereas Rust may pick either.
+fn break_tie(n: f64, digits: String, exponent: i32) -> String {
+    // Every double has an exact decimal expansion of at most 767 significant digits
+    let exact = format!("{:.800e}", n);
+    let (mantissa, exact_exponent) = exact.split_once('e').unwrap_or_default();
+    let exact_digits = mantissa.replace('.', "");
+    let exact_digits = exact_digits.trim_end_matches('0');
+    let k = digits.len();
+    let is_tie = exact_exponent.parse() == Ok(exponent)
+        && exact_digits.len() == k + 1
+        && exact_digits.ends_with('5');
+    let last = digits.as_bytes()[k - 1] - b'0';
+    if !is_tie || last.is_multiple_of(2) {
+        return digits;
+    }
+    let floor = &exact_digits[..k];
+    let other_last = if digits == floor { last + 1 } else { last - 1 };
+    if other_last > 9 {
+        return digits;
+    }
+    let other = format!("{}{}", &digits[..k - 1], other_last);
+    // Both candidates are as close, but the even one must still read back as `n`
+    let parsed = format!("0.{}e{}", other, exponent + 1).parse::<f64>();
+    if parsed == Ok(n) {
+        other
+    } else {
+        digits
+    }
+}
+
+impl<'de> Deserialize<'de> for JcsValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(JcsVisitor)
+    }
+}
+
+struct JcsVisitor;
+
+impl<'de> Visitor<'de> for JcsVisitor {
+    type Value = JcsValue;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a JSON value")
+    }
+
+    fn visit_unit<E>(self) -> Result<JcsValue, E> {
+        Ok(JcsValue::Null)
+    }
+
+    fn visit_bool<E>(self, b: bool) -> Result<JcsValue, E> {
+        Ok(JcsValue::Bool(b))
+    }
+
+    // JCS treats every number as an IEEE 754 double, as ECMAScript does
+    fn visit_i64<E>(self, n: i64) -> Result<JcsValue, E> {
+        Ok(JcsValue::Number(n as f64))
+    }
+
+    fn visit_u64<E>(self, n: u64) -> Result<JcsValue, E> {
+        Ok(JcsValue::Number(n as f64))
+    }
+
+    fn visit_f64<E>(self, n: f64) -> Result<JcsValue, E> {
+        Ok(JcsValue::Number(n))
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<JcsValue, E> {
+        Ok(JcsValue::String(s.to_owned()))
+    }
+
+    fn visit_string<E>(self, s: String) -> Result<JcsValue, E> {
+        Ok(JcsValue::String(s))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<JcsValue, A::Error> {
+        let mut values = Vec::new();
+        while let Some(value) = seq.next_element()? {
+            values.push(value);
+        }
+        Ok(JcsValue::Array(values))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<JcsValue, A::Error> {
+        let mut members: Vec<(String, JcsValue)> = Vec::new();
+        let mut names = HashSet::new();
+        while let Some((name, value)) = map.next_entry::<String, JcsValue>()? {
+            if !names.insert(name.clone()) {
+                return Err(de::Error::custom(format!(
+                    "duplicate member name `{}`",
+                    name
+                )));
+            }
+            members.push((name, value));
+        }
+        Ok(JcsValue::Object(members))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The IEEE 754 doubles of appendix B of RFC8785 and their serialization.
+    #[test]
+    fn rfc8785_numbers() {
+        let cases = [
+            (0x0000000000000000, "0"),
+            (0x8000000000000000, "0"),
+            (0x0000000000000001, "5e-324"),
+            (0x8000000000000001, "-5e-324"),
+            (0x7fefffffffffffff, "1.7976931348623157e+308"),
+            (0xffefffffffffffff, "-1.7976931348623157e+308"),
+            (0x4340000000000000, "9007199254740992"),
+            (0xc340000000000000, "-9007199254740992"),
+            (0x4430000000000000, "295147905179352830000"),
+            (0x44b52d02c7e14af5, "9.999999999999997e+22"),
+            (0x44b52d02c7e14af6, "1e+23"),
+            (0x44b52d02c7e14af7, "1.0000000000000001e+23"),
+            (0x444b1ae4d6e2ef4e, "999999999999999700000"),
+            (0x444b1ae4d6e2ef4f, "999999999999999900000"),
+            (0x444b1ae4d6e2ef50, "1e+21"),
+            (0x3eb0c6f7a0b5ed8c, "9.999999999999997e-7"),
+            (0x3eb0c6f7a0b5ed8d, "0.000001"),
+            (0x41b3de4355555553, "333333333.3333332"),
+            (0x41b3de4355555554, "333333333.33333325"),
+            (0x41b3de4355555555, "333333333.3333333"),
+            (0x41b3de4355555556, "333333333.3333334"),
+            (0x41b3de4355555557, "333333333.33333343"),
+            (0xbecbf647612f3696, "-0.0000033333333333333333"),
+            // Exactly halfway between two 17 digit candidates: the even one is picked
+            (0x43143ff3c1cb0959, "1424953923781206.2"),
+        ];
+        for (bits, expected) in &cases {
+            let n = f64::from_bits(*bits);
+            assert_eq!(ecmascript_number(n).unwrap(), *expected, "{:016x}", bits);
+            // As parsed from a document, whatever the number looked like
+            assert_eq!(
+                canonicalize_json(format!("{:e}", n).as_bytes()).unwrap(),
+                expected.as_bytes(),
+                "{:016x}",
+                bits
+            );
+        }
+        assert!(ecmascript_number(f64::from_bits(0x7fffffffffffffff)).is_err());
+        assert!(ecmascript_number(f64::from_bits(0x7ff0000000000000)).is_err());
+    }
+
+    /// The example of section 3.2.2 of RFC8785.
+    #[test]
+    fn rfc8785_example() {
+        let json = br#"{
+  "numbers": [333333333.33333329, 1E30, 4.50, 2e-3, 0.000000000000000000000000001],
+  "string": "\u20ac$\u000F\u000aA'\u0042\u0022\u005c\\\"\/",
+  "literals": [null, true, false]
+}"#;
+        assert_eq!(
+            String::from_utf8(canonicalize_json(json).unwrap()).unwrap(),
+            r#"{"literals":[null,true,false],"numbers":[333333333.3333333,1e+30,4.5,0.002,1e-27],"string":"€$\u000f\nA'B\"\\\\\"/"}"#
+        );
+    }
+
+    /// The example of section 3.2.3 of RFC8785: names are sorted by their UTF-16 code units, so
+    /// that U+1F600, encoded as a surrogate pair starting with 0xD83D, sorts before U+FB33.
+    #[test]
+    fn rfc8785_sorting() {
+        let json = br#"{
+  "\u20ac": "Euro Sign",
+  "\r": "Carriage Return",
+  "\ufb33": "Hebrew Letter Dalet With Dagesh",
+  "1": "One",
+  "\ud83d\ude00": "Emoji: Grinning Face",
+  "\u0080": "Control",
+  "\u00f6": "Latin Small Letter O With Diaeresis"
+}"#;
+        assert_eq!(
+            String::from_utf8(canonicalize_json(json).unwrap()).unwrap(),
+            "{\"\\r\":\"Carriage Return\",\"1\":\"One\",\"\u{80}\":\"Control\",\
+             \"\u{f6}\":\"Latin Small Letter O With Diaeresis\",\"\u{20ac}\":\"Euro Sign\",\
+             \"\u{1f600}\":\"Emoji: Grinning Face\",\
+             \"\u{fb33}\":\"Hebrew Letter Dalet With Dagesh\"}"
+        );
+    }
+
+    #[test]
+    fn duplicate_names_are_rejected() {
+        assert!(canonicalize_json(br#"{"a":1,"a":2}"#).is_err());
+        assert!(canonicalize_json(br#"{"a":{"b":1,"b":1}}"#).is_err());
+        assert!(canonicalize_json(br#"[{"a":1},{"a":2}]"#).is_ok());
+    }
+
+    #[test]
+    fn minify_keeps_strings() {
+        assert_eq!(
+            minify_json(b"{ \"a b\" : [1, \"c\\\" d\"],\n\t\"e\": 1.50 }").unwrap(),
+            &b"{\"a b\":[1,\"c\\\" d\"],\"e\":1.50}"[..]
+        );
+        assert!(minify_json(b"{\"a\": }").is_err());
+    }
+}
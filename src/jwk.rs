@@ -0,0 +1,268 @@
+use crate::{base64_decode, base64_encode, Algorithm};
+use anyhow::Context;
+use openssl::{
+    bn::{BigNum, BigNumContext},
+    ec::{EcGroup, EcKey, EcKeyRef, EcPoint},
+    pkey::{HasPublic, Private, Public},
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// An Elliptic Curve JSON Web Key, as defined in section 6.2 of RFC7518.
+#[derive(Deserialize)]
+struct EcJwk {
+    kty: String,
+    crv: String,
+    x: Option<String>,
+    y: Option<String>,
+    d: Option<String>,
+}
+
+impl EcJwk {
+    /// The algorithm signing with keys on the curve of the JWK.
+    fn alg(&self) -> Result<Algorithm, anyhow::Error> {
+        if self.kty != "EC" {
+            return Err(anyhow::anyhow!(
+                "The key must be an Elliptic Curve key, found a JWK with `kty` {}.",
+                self.kty
+            ));
+        }
+        [Algorithm::ES256, Algorithm::ES384, Algorithm::ES512]
+            .iter()
+            .copied()
+            .find(|alg| alg.curve_name() == self.crv)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Unsupported JWK curve `{}`, expected one of P-256, P-384 or P-521.",
+                    self.crv
+                )
+            })
+    }
+
+    /// The public key `x` and `y`, if provided.
+    fn public_key(&self, group: &EcGroup) -> Result<Option<EcKey<Public>>, anyhow::Error> {
+        let (x, y) = match (&self.x, &self.y) {
+            (Some(x), Some(y)) => (x, y),
+            _ => return Ok(None),
+        };
+        let x = BigNum::from_slice(&base64_decode(x).context("Failed to base64 decode `x`.")?)?;
+        let y = BigNum::from_slice(&base64_decode(y).context("Failed to base64 decode `y`.")?)?;
+        let public_key = EcKey::from_public_key_affine_coordinates(group, &x, &y)
+            .context("`x` and `y` are not a point of the curve.")?;
+        Ok(Some(public_key))
+    }
+}
+
+/// Parse an Elliptic Curve private key in JWK format, i.e. with `kty` set to `EC`, `crv` to one of
+/// `P-256`, `P-384` or `P-521` and the private key `d`. The public key is derived from `d` and,
+/// when provided, checked against `x` and `y`.
+pub fn private_key_from_jwk(jwk: &[u8]) -> Result<EcKey<Private>, anyhow::Error> {
+    let jwk: EcJwk =
+        serde_json::from_slice(jwk).context("Failed to parse the private key as JWK.")?;
+    let alg = jwk.alg()?;
+    let d = jwk
+        .d
+        .as_deref()
+        .context("The JWK is missing the private key `d`, it is a public key.")?;
+
+    let group = EcGroup::from_curve_name(alg.curve())?;
+    let mut ctx = BigNumContext::new()?;
+    let d = BigNum::from_slice(&base64_decode(d).context("Failed to base64 decode `d`.")?)?;
+    let mut public_key = EcPoint::new(&group)?;
+    public_key.mul_generator(&group, &d, &ctx)?;
+
+    if let Some(expected) = jwk.public_key(&group)? {
+        if !public_key.eq(&group, expected.public_key(), &mut ctx)? {
+            return Err(anyhow::anyhow!(
+                "The public key `x` and `y` of the JWK does not match its private key `d`."
+            ));
+        }
+    }
+
+    let private_key = EcKey::from_private_components(&group, &d, &public_key)?;
+    private_key.check_key().context("Key verification failed")?;
+    Ok(private_key)
+}
+
+/// Parse an Elliptic Curve public key in JWK format, i.e. with `kty` set to `EC`, `crv` to one of
+/// `P-256`, `P-384` or `P-521` and the public key `x` and `y`.
+pub fn public_key_from_jwk(jwk: &Value) -> Result<EcKey<Public>, anyhow::Error> {
+    let jwk = EcJwk::deserialize(jwk).context("Failed to parse the public key as JWK.")?;
+    let group = EcGroup::from_curve_name(jwk.alg()?.curve())?;
+    let public_key = jwk
+        .public_key(&group)?
+        .context("The JWK is missing the public key `x` and `y`.")?;
+    public_key.check_key().context("Key verification failed")?;
+    Ok(public_key)
+}
+
+/// The public JWK of a key, identified by `kid`, for verifying signatures made using `alg`.
+///
+/// The coordinates are encoded as described in section 6.2.1 of RFC7518.
+pub fn public_jwk<T: HasPublic>(pkey: &EcKeyRef<T>, kid: &str) -> Result<Value, anyhow::Error> {
+    let alg = Algorithm::from_curve(pkey.group().curve_name())?;
+    let mut ctx = BigNumContext::new()?;
+    let mut x = BigNum::new()?;
+    let mut y = BigNum::new()?;
+    pkey.public_key()
+        .affine_coordinates_gfp(pkey.group(), &mut x, &mut y, &mut ctx)?;
+    // The coordinates have the fixed length of the curve, leading zeros included
+    let coordinate_len = alg.coordinate_len() as i32;
+
+    Ok(json!({
+        "kty": "EC",
+        "crv": alg.curve_name(),
+        "x": base64_encode(&x.to_vec_padded(coordinate_len)?),
+        "y": base64_encode(&y.to_vec_padded(coordinate_len)?),
+        "kid": kid,
+        "alg": alg.name(),
+        "use": "sig",
+    }))
+}
+
+/// The JWK thumbprint of a public key, as described in RFC7638: the base64 encoded SHA-256 digest
+/// of its required members, in lexicographic order and without whitespace.
+pub fn jwk_thumbprint<T: HasPublic>(pkey: &EcKeyRef<T>) -> Result<String, anyhow::Error> {
+    let jwk = public_jwk(pkey, "")?;
+    let required = json!({
+        "crv": jwk["crv"],
+        "kty": jwk["kty"],
+        "x": jwk["x"],
+        "y": jwk["y"],
+    });
+    Ok(base64_encode(&openssl::sha::sha256(
+        serde_json::to_string(&required)?.as_bytes(),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate_key;
+
+    /// The P-256 private key of appendix A.2 of RFC7517.
+    const RFC7517_A2_X: &str = "MKBCTNIcKUSDii11ySs3526iDZ8AiTo7Tu6KPAqv7D4";
+    const RFC7517_A2_Y: &str = "4Etl6SRW2YiLUrN5vfvVHuhp7x8PxltmWWlbbM4IFyM";
+    const RFC7517_A2_D: &str = "870MB6gfuTJ4HtUnUvYMyJpr5eUZNP4Bk43bVdj3eAE";
+
+    #[test]
+    fn parse_private_jwks() {
+        let jwk = json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "x": RFC7517_A2_X,
+            "y": RFC7517_A2_Y,
+            "d": RFC7517_A2_D,
+            "use": "enc",
+            "kid": "1",
+        });
+        let key = private_key_from_jwk(jwk.to_string().as_bytes()).unwrap();
+        assert_eq!(key.group().curve_name(), Some(Algorithm::ES256.curve()));
+        assert_eq!(
+            key.private_key().to_vec(),
+            base64_decode(RFC7517_A2_D).unwrap()
+        );
+        let public = public_jwk(&key, "1").unwrap();
+        assert_eq!(public["x"], RFC7517_A2_X);
+        assert_eq!(public["y"], RFC7517_A2_Y);
+
+        // `x` and `y` are optional, the public key being derived from `d`
+        let jwk = json!({"kty": "EC", "crv": "P-256", "d": RFC7517_A2_D});
+        let derived = private_key_from_jwk(jwk.to_string().as_bytes()).unwrap();
+        assert_eq!(public_jwk(&derived, "1").unwrap(), public);
+    }
+
+    #[test]
+    fn reject_invalid_private_jwks() {
+        let other = public_jwk(&generate_key(Algorithm::ES256).unwrap(), "").unwrap();
+        let jwk = json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "x": other["x"],
+            "y": other["y"],
+            "d": RFC7517_A2_D,
+        });
+        assert_eq!(
+            private_key_from_jwk(jwk.to_string().as_bytes())
+                .unwrap_err()
+                .to_string(),
+            "The public key `x` and `y` of the JWK does not match its private key `d`."
+        );
+
+        let public = json!({"kty": "EC", "crv": "P-256", "x": RFC7517_A2_X, "y": RFC7517_A2_Y});
+        assert_eq!(
+            private_key_from_jwk(public.to_string().as_bytes())
+                .unwrap_err()
+                .to_string(),
+            "The JWK is missing the private key `d`, it is a public key."
+        );
+        let rsa = json!({"kty": "RSA", "crv": "P-256", "d": RFC7517_A2_D});
+        assert!(private_key_from_jwk(rsa.to_string().as_bytes()).is_err());
+        let curve = json!({"kty": "EC", "crv": "secp256k1", "d": RFC7517_A2_D});
+        assert!(private_key_from_jwk(curve.to_string().as_bytes()).is_err());
+    }
+
+    #[test]
+    fn public_jwks_round_trip() {
+        for alg in &[Algorithm::ES256, Algorithm::ES384, Algorithm::ES512] {
+            let key = generate_key(*alg).unwrap();
+            let jwk = public_jwk(&key, "kid").unwrap();
+            let public_key = public_key_from_jwk(&jwk).unwrap();
+            let mut ctx = BigNumContext::new().unwrap();
+            assert!(public_key
+                .public_key()
+                .eq(key.group(), key.public_key(), &mut ctx)
+                .unwrap());
+            assert_eq!(
+                jwk.as_object().unwrap().keys().collect::<Vec<_>>(),
+                ["kty", "crv", "x", "y", "kid", "alg", "use"]
+            );
+            assert_eq!(jwk["crv"], alg.curve_name());
+            assert_eq!(jwk["alg"], alg.name());
+        }
+    }
+
+    #[test]
+    fn public_jwk_coordinates_keep_their_leading_zeros() {
+        // The first byte of a P-521 coordinate is 0 for about half of the keys
+        let key = std::iter::repeat_with(|| generate_key(Algorithm::ES512).unwrap())
+            .find(|key| {
+                let mut ctx = BigNumContext::new().unwrap();
+                let mut x = BigNum::new().unwrap();
+                let mut y = BigNum::new().unwrap();
+                key.public_key()
+                    .affine_coordinates_gfp(key.group(), &mut x, &mut y, &mut ctx)
+                    .unwrap();
+                x.num_bytes() < 66
+            })
+            .unwrap();
+        let jwk = public_jwk(&key, "kid").unwrap();
+        for coordinate in &["x", "y"] {
+            let decoded = base64_decode(jwk[coordinate].as_str().unwrap()).unwrap();
+            assert_eq!(decoded.len(), 66);
+        }
+        assert_eq!(base64_decode(jwk["x"].as_str().unwrap()).unwrap()[0], 0);
+        public_key_from_jwk(&jwk).unwrap();
+    }
+
+    #[test]
+    fn thumbprints_hash_the_required_members_in_order() {
+        let key = generate_key(Algorithm::ES512).unwrap();
+        let jwk = public_jwk(&key, "kid").unwrap();
+        let required = format!(
+            r#"{{"crv":"P-521","kty":"EC","x":"{}","y":"{}"}}"#,
+            jwk["x"].as_str().unwrap(),
+            jwk["y"].as_str().unwrap()
+        );
+        assert_eq!(
+            jwk_thumbprint(&key).unwrap(),
+            base64_encode(&openssl::sha::sha256(required.as_bytes()))
+        );
+        // The thumbprint does not depend on the optional members
+        let public_key = public_key_from_jwk(&jwk).unwrap();
+        assert_eq!(
+            jwk_thumbprint(&public_key).unwrap(),
+            jwk_thumbprint(&key).unwrap()
+        );
+    }
+}
@@ -0,0 +1,468 @@
+use crate::{public_key_fingerprint, v2_signing_payload, Algorithm, Header, SigningKey};
+use anyhow::Context;
+use base64::URL_SAFE_NO_PAD;
+use openssl::{
+    bn::BigNum,
+    ec::{EcKey, EcKeyRef},
+    ecdsa::EcdsaSig,
+    pkey::{Private, Public},
+};
+use serde_json::{json, Value};
+use std::{fmt, str::FromStr};
+
+/// Signs payloads and requests with a private key, identified by a `kid`.
+pub struct Signer {
+    key: Box<dyn SigningKey>,
+    kid: String,
+    alg: Algorithm,
+    /// Whether the payload is signed base64url encoded, as usual, or as is (RFC7797).
+    b64: bool,
+}
+
+impl Signer {
+    /// Create a signer using the algorithm matching the curve of the private key.
+    ///
+    /// `kid` is the certificate id associated to the public certificate uploaded in
+    /// TrueLayer's Console.
+    pub fn new(
+        key: impl SigningKey + 'static,
+        kid: impl Into<String>,
+    ) -> Result<Self, anyhow::Error> {
+        Ok(Signer {
+            alg: key.alg()?,
+            key: Box::new(key),
+            kid: kid.into(),
+            b64: true,
+        })
+    }
+
+    /// Sign using `alg`, failing if the private key is not on the curve it requires.
+    pub fn with_algorithm(mut self, alg: Algorithm) -> Result<Self, anyhow::Error> {
+        if alg != self.alg {
+            return Err(anyhow::anyhow!(
+                "Signing using {} requires a {} private key, found a {} key.",
+                alg,
+                alg.curve_name(),
+                self.alg.curve_name()
+            ));
+        }
+        self.alg = alg;
+        Ok(self)
+    }
+
+    /// Sign the payload as is rather than base64url encoded, as described in RFC7797, adding
+    /// `"b64": false` and `"crit": ["b64"]` to the protected header.
+    pub fn with_unencoded_payload(mut self) -> Self {
+        self.b64 = false;
+        self
+    }
+
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    pub fn alg(&self) -> Algorithm {
+        self.alg
+    }
+
+    /// The public key of the private key.
+    pub fn public_key(&self) -> Result<EcKey<Public>, anyhow::Error> {
+        self.key.public_key()
+    }
+
+    /// Check that the private key matches `public_key`, e.g. the one of the certificate uploaded
+    /// in TrueLayer's Console for the `kid`, so that a wrong key fails before reaching the API.
+    pub fn check_public_key(&self, public_key: &EcKeyRef<Public>) -> Result<(), anyhow::Error> {
+        let expected = public_key_fingerprint(public_key)?;
+        let actual = public_key_fingerprint(&*self.public_key()?)?;
+        if actual != expected {
+            return Err(anyhow::anyhow!(
+                "The private key does not match the certificate of kid {}: the public key of the \
+                 private key has fingerprint {}, the one of the certificate {}.",
+                self.kid,
+                actual,
+                expected
+            ));
+        }
+        Ok(())
+    }
+
+    /// Sign a payload, as for the `X-TL-Signature` header of the Payouts/Paydirect API.
+    pub fn sign(&self, payload: &[u8]) -> Result<DetachedJws, anyhow::Error> {
+        self.sign_with_header(&self.jws_header(), payload)
+    }
+
+    /// Sign a request using TrueLayer's request signing v2, as for the `Tl-Signature` header.
+    /// `headers` are signed in the order they are given.
+    pub fn sign_request(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[Header],
+        body: &[u8],
+    ) -> Result<DetachedJws, anyhow::Error> {
+        let mut jws_header = self.jws_header();
+        jws_header["tl_version"] = json!("2");
+        jws_header["tl_headers"] = json!(headers
+            .iter()
+            .map(Header::name)
+            .collect::<Vec<_>>()
+            .join(","));
+        let jws_payload = v2_signing_payload(method, path, headers, body);
+        self.sign_with_header(&jws_header, &jws_payload)
+    }
+
+    fn jws_header(&self) -> Value {
+        let mut jws_header = json!({
+            "alg": self.alg.name(),
+            "kid": self.kid,
+        });
+        if !self.b64 {
+            jws_header["b64"] = json!(false);
+            jws_header["crit"] = json!(["b64"]);
+        }
+        jws_header
+    }
+
+    fn sign_with_header(
+        &self,
+        jws_header: &Value,
+        jws_payload: &[u8],
+    ) -> Result<DetachedJws, anyhow::Error> {
+        // Not through `get_jws`, as an unencoded payload may not fit in the compact serialization
+        let (header, signature) = sign_jws(jws_header, jws_payload, &*self.key)?;
+        Ok(DetachedJws { header, signature })
+    }
+}
+
+/// A JWS with detached payload, i.e. of the form `header..signature`, as described in
+/// appendix F of RFC7515.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DetachedJws {
+    /// The base64 encoded protected header.
+    header: String,
+    /// The base64 encoded signature.
+    signature: String,
+}
+
+impl DetachedJws {
+    /// Detach the payload of a JWS in compact serialization, as returned by `get_jws`.
+    pub fn from_compact(jws: &str) -> Result<Self, anyhow::Error> {
+        match jws.split('.').collect::<Vec<_>>().as_slice() {
+            [header, _, signature] => Ok(DetachedJws {
+                header: (*header).to_owned(),
+                signature: (*signature).to_owned(),
+            }),
+            parts => Err(anyhow::anyhow!(
+                "The JWS must be made of three `.` separated parts, found {}.",
+                parts.len()
+            )),
+        }
+    }
+
+    /// The decoded protected header.
+    pub fn header(&self) -> Result<Value, anyhow::Error> {
+        serde_json::from_slice(
+            &base64_decode(&self.header).context("Failed to base64 decode the JWS header.")?,
+        )
+        .context("The JWS header is not valid JSON.")
+    }
+
+    /// Whether the payload is signed base64url encoded, i.e. unless the protected header has
+    /// `"b64": false` (RFC7797).
+    pub fn is_payload_encoded(&self) -> Result<bool, anyhow::Error> {
+        is_payload_encoded(&self.header()?)
+    }
+
+    /// The JWS Signing Input the signature is computed over, i.e. the base64 encoded protected
+    /// header and payload, separated by a `.`. The payload is left as is with `"b64": false`.
+    pub fn signing_input(&self, jws_payload: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        Ok(signing_input(
+            &self.header,
+            jws_payload,
+            self.is_payload_encoded()?,
+        ))
+    }
+
+    /// The JWS in compact serialization, with `jws_payload` attached.
+    ///
+    /// Fails for an unencoded payload which is not valid UTF-8 or contains a `.`, as those can
+    /// only be detached.
+    pub fn attach(&self, jws_payload: &[u8]) -> Result<String, anyhow::Error> {
+        let jws_payload = compact_payload(jws_payload, self.is_payload_encoded()?)?;
+        Ok(format!(
+            "{}.{}.{}",
+            self.header, jws_payload, self.signature
+        ))
+    }
+
+    /// The algorithm of the protected header.
+    pub fn alg(&self) -> Result<Algorithm, anyhow::Error> {
+        self.header()?
+            .get("alg")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow::anyhow!("The JWS header is missing `alg`."))?
+            .parse()
+    }
+
+    /// Verify the signature against the detached payload and the provided public key.
+    pub fn verify(&self, jws_payload: &[u8], pkey: &EcKeyRef<Public>) -> Result<(), anyhow::Error> {
+        let alg = self.alg()?;
+        let signature =
+            base64_decode(&self.signature).context("Failed to base64 decode the JWS signature.")?;
+        let to_be_verified = self.signing_input(jws_payload)?;
+        if !verify_ecdsa(&to_be_verified, &signature, alg, pkey)? {
+            return Err(anyhow::anyhow!(
+                "Invalid signature: the JWS was not produced by the private key associated to the \
+                 certificate, or the payload differs from the one that was signed."
+            ));
+        }
+        Ok(())
+    }
+
+    /// Verify a request signing v2 signature against the request and the provided public key.
+    ///
+    /// The signed headers are picked from `headers` in the order listed by the `tl_headers` of the
+    /// protected header, ignoring case; other headers are ignored.
+    pub fn verify_request(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[Header],
+        body: &[u8],
+        pkey: &EcKeyRef<Public>,
+    ) -> Result<(), anyhow::Error> {
+        let jws_header = self.header()?;
+        match jws_header.get("tl_version").and_then(Value::as_str) {
+            Some("2") => {}
+            Some(tl_version) => {
+                return Err(anyhow::anyhow!(
+                    "Unsupported `tl_version` {}, expected 2.",
+                    tl_version
+                ))
+            }
+            None => {
+                return Err(anyhow::anyhow!(
+                "The JWS header is missing `tl_version`, it is not a request signing v2 signature."
+            ))
+            }
+        }
+
+        let tl_headers = jws_header
+            .get("tl_headers")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let signed_headers = tl_headers
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                headers
+                    .iter()
+                    .find(|header| header.name().eq_ignore_ascii_case(name))
+                    .map(|header| Header::new(name, header.value()))
+                    .ok_or_else(|| anyhow::anyhow!("The signed header `{}` is missing.", name))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.verify(
+            &v2_signing_payload(method, path, &signed_headers, body),
+            pkey,
+        )
+    }
+}
+
+impl FromStr for DetachedJws {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split('.').collect::<Vec<_>>().as_slice() {
+            [header, "", signature] => Ok(DetachedJws {
+                header: (*header).to_owned(),
+                signature: (*signature).to_owned(),
+            }),
+            [_, _, _] => Err(anyhow::anyhow!(
+                "The JWS must have a detached payload, i.e. be of the form `header..signature`."
+            )),
+            parts => Err(anyhow::anyhow!(
+                "The JWS must be made of three `.` separated parts, found {}.",
+                parts.len()
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DetachedJws {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Omit the payload for a JWS with detached payload
+        write!(f, "{}..{}", self.header, self.signature)
+    }
+}
+
+/// Get a JWS using the ES256, ES384 or ES512 signing scheme, matching the curve of the key.
+///
+/// Check section A.4 of RFC7515 for the details: https://www.rfc-editor.org/rfc/rfc7515.txt
+///
+/// With `"b64": false` and `"crit": ["b64"]` in `jws_header`, the payload is signed and attached
+/// as is, as described in RFC7797: https://www.rfc-editor.org/rfc/rfc7797.txt
+pub fn get_jws<K: SigningKey + ?Sized>(
+    jws_header: &Value,
+    jws_payload: &[u8],
+    key: &K,
+) -> Result<String, anyhow::Error> {
+    let b64 = is_payload_encoded(jws_header)?;
+    let (header, signature) = sign_jws(jws_header, jws_payload, key)?;
+    Ok(format!(
+        "{}.{}.{}",
+        header,
+        compact_payload(jws_payload, b64)?,
+        signature
+    ))
+}
+
+/// Sign a payload, returning the base64 encoded protected header and signature.
+fn sign_jws<K: SigningKey + ?Sized>(
+    jws_header: &Value,
+    jws_payload: &[u8],
+    key: &K,
+) -> Result<(String, String), anyhow::Error> {
+    let alg = key.alg()?;
+    if let Some(header_alg) = jws_header.get("alg").and_then(Value::as_str) {
+        if header_alg != alg.name() {
+            return Err(anyhow::anyhow!(
+                "The JWS header `alg` is {}, but the key signs using {}.",
+                header_alg,
+                alg
+            ));
+        }
+    }
+    let header = base64_encode(serde_json::to_string(&jws_header)?.as_bytes());
+    let to_be_signed = signing_input(&header, jws_payload, is_payload_encoded(jws_header)?);
+    let signature = base64_encode(&key.sign(&to_be_signed)?);
+    Ok((header, signature))
+}
+
+/// Whether the payload is base64url encoded according to the `b64` header parameter of RFC7797,
+/// which must then be listed as critical.
+fn is_payload_encoded(jws_header: &Value) -> Result<bool, anyhow::Error> {
+    let b64 = match jws_header.get("b64") {
+        None => true,
+        Some(Value::Bool(b64)) => *b64,
+        Some(b64) => {
+            return Err(anyhow::anyhow!(
+                "The JWS header `b64` must be a boolean, found {}.",
+                b64
+            ))
+        }
+    };
+    let crit = match jws_header.get("crit") {
+        None => &[][..],
+        Some(Value::Array(crit)) if !crit.is_empty() => crit.as_slice(),
+        Some(crit) => {
+            return Err(anyhow::anyhow!(
+                "The JWS header `crit` must be a non-empty array, found {}.",
+                crit
+            ))
+        }
+    };
+    // `b64` is the only extension understood, see section 4.1.11 of RFC7515
+    if let Some(unsupported) = crit.iter().find(|name| name.as_str() != Some("b64")) {
+        return Err(anyhow::anyhow!(
+            "Unsupported critical JWS header parameter {}.",
+            unsupported
+        ));
+    }
+    if !b64 && crit.is_empty() {
+        return Err(anyhow::anyhow!(
+            "The JWS header has `\"b64\": false` but is missing `\"crit\": [\"b64\"]`, as RFC7797 \
+             requires."
+        ));
+    }
+    Ok(b64)
+}
+
+/// The JWS Signing Input of RFC7515, or of RFC7797 for an unencoded payload.
+fn signing_input(header: &str, jws_payload: &[u8], b64: bool) -> Vec<u8> {
+    let mut signing_input = format!("{}.", header).into_bytes();
+    if b64 {
+        signing_input.extend_from_slice(base64_encode(jws_payload).as_bytes());
+    } else {
+        signing_input.extend_from_slice(jws_payload);
+    }
+    signing_input
+}
+
+/// The payload part of a JWS in compact serialization.
+fn compact_payload(jws_payload: &[u8], b64: bool) -> Result<String, anyhow::Error> {
+    if b64 {
+        return Ok(base64_encode(jws_payload));
+    }
+    match std::str::from_utf8(jws_payload) {
+        Ok(jws_payload) if !jws_payload.contains('.') => Ok(jws_payload.to_owned()),
+        _ => Err(anyhow::anyhow!(
+            "An unencoded payload which is not valid UTF-8 or contains a `.` cannot be attached \
+             to a JWS in compact serialization, detach it instead."
+        )),
+    }
+}
+
+/// Sign a payload using the provided private key and return the signature as a base64 encoded string.
+///
+/// Check section A.4 of RFC7515 for the details: https://www.rfc-editor.org/rfc/rfc7515.txt
+pub fn sign_ecdsa(
+    payload: &[u8],
+    alg: Algorithm,
+    pkey: &EcKeyRef<Private>,
+) -> Result<String, anyhow::Error> {
+    if pkey.group().curve_name() != Some(alg.curve()) {
+        return Err(anyhow::anyhow!(
+            "The underlying elliptic curve must be {} to sign using {}.",
+            alg.curve_name(),
+            alg
+        ));
+    }
+    Ok(base64_encode(&SigningKey::sign(pkey, payload)?))
+}
+
+/// Verify a base64 decoded ES256, ES384 or ES512 signature of a payload using the provided
+/// public key.
+///
+/// Check section A.4 of RFC7515 for the details: https://www.rfc-editor.org/rfc/rfc7515.txt
+pub fn verify_ecdsa(
+    payload: &[u8],
+    signature: &[u8],
+    alg: Algorithm,
+    pkey: &EcKeyRef<Public>,
+) -> Result<bool, anyhow::Error> {
+    if pkey.group().curve_name() != Some(alg.curve()) {
+        return Err(anyhow::anyhow!(
+            "The underlying elliptic curve must be {} to verify using {}.",
+            alg.curve_name(),
+            alg
+        ));
+    }
+    if signature.len() != 2 * alg.coordinate_len() {
+        return Err(anyhow::anyhow!(
+            "An {} signature must be {} bytes long, found {} bytes.",
+            alg,
+            2 * alg.coordinate_len(),
+            signature.len()
+        ));
+    }
+    let (r, s) = signature.split_at(alg.coordinate_len());
+    let structured_signature =
+        EcdsaSig::from_private_components(BigNum::from_slice(r)?, BigNum::from_slice(s)?)?;
+    let hash = openssl::hash::hash(alg.digest(), payload)?;
+
+    Ok(structured_signature.verify(&hash, pkey)?)
+}
+
+/// Base64 encoding according to RFC7515 - see `Base64url` in section 2.
+pub fn base64_encode(payload: &[u8]) -> String {
+    base64::encode_config(payload, URL_SAFE_NO_PAD)
+}
+
+/// Base64 decoding according to RFC7515 - see `Base64url` in section 2.
+pub fn base64_decode(payload: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    base64::decode_config(payload, URL_SAFE_NO_PAD)
+}
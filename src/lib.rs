@@ -0,0 +1,57 @@
+//! Sign requests to TrueLayer's APIs with a JWS with detached payload, exactly as the `tlsign`
+//! command line interface does.
+//!
+//! ```
+//! use tlsign::{generate_key, Algorithm, Signer};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let private_key = generate_key(Algorithm::ES512)?;
+//! let signer = Signer::new(private_key, "45fc75cf-5649-4134-84b3-192c2c78e990")?;
+//!
+//! // Legacy Payouts/Paydirect signature of the payload alone, sent as `X-TL-Signature`
+//! let jws = signer.sign(br#"{"amount_in_minor":100}"#)?;
+//!
+//! // Request signing v2 signature, sent as `Tl-Signature`
+//! let jws = signer.sign_request(
+//!     "POST",
+//!     "/v3/payments",
+//!     &["Idempotency-Key:1234".parse()?],
+//!     br#"{"amount_in_minor":100}"#,
+//! )?;
+//! println!("{}", jws);
+//! # Ok(())
+//! # }
+//! ```
+#[cfg(unix)]
+mod agent;
+mod alg;
+mod deterministic;
+mod json;
+mod jwk;
+mod jws;
+mod key;
+#[cfg(unix)]
+mod pkcs11;
+mod request;
+mod signing_key;
+mod webhook;
+
+#[cfg(unix)]
+pub use agent::{Agent, AgentClient, AgentKey, AgentKeyInfo, AUTH_SOCK_ENV};
+pub use alg::Algorithm;
+pub use deterministic::DeterministicKey;
+pub use json::{canonicalize_json, minify_json};
+pub use jwk::{jwk_thumbprint, private_key_from_jwk, public_jwk, public_key_from_jwk};
+pub use jws::{
+    base64_decode, base64_encode, get_jws, sign_ecdsa, verify_ecdsa, DetachedJws, Signer,
+};
+pub use key::{
+    generate_key, is_encrypted_pem, private_key_from_der, private_key_from_pem,
+    private_key_from_pem_passphrase, private_key_from_pkcs12, public_key_fingerprint,
+    public_key_from_pem, self_signed_certificate, KeyFormat,
+};
+#[cfg(unix)]
+pub use pkcs11::Pkcs11Key;
+pub use request::{v2_signing_payload, Header, HeaderParseError};
+pub use signing_key::SigningKey;
+pub use webhook::{WebhookVerifier, TRUELAYER_WEBHOOK_JKUS};
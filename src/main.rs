@@ -1,38 +1,662 @@
+mod config;
+mod http;
+mod inspect;
+mod passphrase;
+mod proxy;
+
 use anyhow::Context;
-use base64::URL_SAFE_NO_PAD;
 use clap::Clap;
-use openssl::{ec::EcKey, ecdsa::EcdsaSig, hash::MessageDigest, nid::Nid, pkey::Private};
+use config::{Config, Profile, Signing};
+use openssl::{
+    ec::EcKey,
+    pkey::{Private, Public},
+};
+use serde::Deserialize;
 use serde_json::{json, Value};
-use std::path::PathBuf;
+use std::{
+    ffi::OsString,
+    fs::OpenOptions,
+    io::{BufRead, Read, Write},
+    path::{Path, PathBuf},
+};
+use tlsign::{
+    canonicalize_json, generate_key, is_encrypted_pem, jwk_thumbprint, minify_json,
+    private_key_from_der, private_key_from_jwk, private_key_from_pem,
+    private_key_from_pem_passphrase, private_key_from_pkcs12, public_jwk, public_key_from_pem,
+    self_signed_certificate, v2_signing_payload, Algorithm, DetachedJws, DeterministicKey, Header,
+    KeyFormat, Signer, WebhookVerifier,
+};
+#[cfg(unix)]
+use tlsign::{AgentClient, Pkcs11Key, AUTH_SOCK_ENV};
 use uuid::Uuid;
 
 /// A small command line interface to sign POST requests for Payouts/Paydirect API.
 #[derive(Clap)]
-struct Command {
-    /// The payload you want to sign.
+enum Command {
+    /// Sign a payload, printing a JWS with detached payload.
+    Sign(Sign),
+    /// Verify a JWS with detached payload against a payload and a public certificate.
+    Verify(Verify),
+    /// Generate a key pair and a self-signed certificate to upload in TrueLayer's Console.
+    Keygen(Keygen),
+    /// Sign a request and send it, printing the response.
+    Send(Send),
+    /// Print the public JWK of a private key, or the JWKS of several private keys.
+    Jwk(Jwk),
+    /// Verify the `Tl-Signature` of a webhook against the keys of a JWKS.
+    VerifyWebhook(VerifyWebhook),
+    /// Decode a JWS and explain what is wrong with it, if anything.
+    Inspect(Inspect),
+    /// Sign the plain HTTP requests of other tools, e.g. Postman, and forward them upstream.
+    Proxy(Proxy),
+    /// Sign a JSON Lines file of requests, loading the private key once.
+    Batch(Batch),
+    /// Hold private keys in memory, signing for other `tlsign` commands over a Unix socket.
+    #[cfg(unix)]
+    Agent(Agent),
+    /// List the keys held by the agent.
+    #[cfg(unix)]
+    AgentKeys(AgentSocket),
+    /// Lock the agent with a passphrase: it refuses to sign until unlocked.
+    #[cfg(unix)]
+    AgentLock(AgentPassphrase),
+    /// Unlock the agent with the passphrase it was locked with.
+    #[cfg(unix)]
+    AgentUnlock(AgentPassphrase),
+}
+
+#[derive(Clap)]
+struct Sign {
+    #[clap(flatten)]
+    body: BodyOptions,
+    #[clap(flatten)]
+    rewrite: RewriteOptions,
+    #[clap(flatten)]
+    key: KeyOptions,
+    #[clap(flatten)]
+    request: RequestOptions,
+    /// Sign the payload as is rather than base64url encoded, as described in RFC7797, adding
+    /// `"b64": false` and `"crit": ["b64"]` to the header. TrueLayer's APIs do not accept such
+    /// signatures, but some partner APIs require them.
+    #[clap(long)]
+    unencoded_payload: bool,
+    /// What to print: the JWS alone, a JSON object describing the signature, i.e. the JWS, the JWS
+    /// with attached payload, the decoded header, the signing input, the algorithm, the `kid`, the
+    /// JWK thumbprint of the key and the SHA-256 of the body, or a curl command sending the signed
+    /// request. With `--canonicalize` or `--minify`, the JWS is followed by the payload to send on
+    /// a second line.
+    #[clap(
+        long,
+        alias = "output",
+        default_value = "jws",
+        possible_values = &["jws", "json", "curl"]
+    )]
+    emit: Emit,
+    /// The URL to send the request to with `--emit curl`.
+    /// With `--path`, the base URL the path is appended to, e.g. `https://api.truelayer.com`,
+    /// without a query string.
+    /// Defaults to the `url` of the profile.
+    #[clap(long)]
+    url: Option<String>,
+    /// The access token sent as the `Authorization: Bearer` header with `--emit curl`.
+    #[clap(long)]
+    token: Option<String>,
+}
+
+/// What `sign` prints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Emit {
+    Jws,
+    Json,
+    Curl,
+}
+
+impl std::str::FromStr for Emit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jws" => Ok(Emit::Jws),
+            "json" => Ok(Emit::Json),
+            "curl" => Ok(Emit::Curl),
+            _ => Err(anyhow::anyhow!("Unknown output `{}`.", s)),
+        }
+    }
+}
+
+/// The private key to sign with and how to identify it.
+#[derive(Clap)]
+struct KeyOptions {
+    /// The filename of the Elliptic Curve private key used to sign, in PEM, DER, JWK or PKCS#12
+    /// format. An encrypted PEM key or a PKCS#12 bundle is decrypted using the passphrase from
+    /// `--passphrase-file`, the `TLSIGN_PASSPHRASE` environment variable or, failing that,
+    /// a prompt.
+    /// Defaults to the `key` of the profile.
     #[clap(long)]
-    body: String,
-    /// The filename of the Elliptic Curve private key used to sign, in PEM format.
+    key: Option<PathBuf>,
+    /// The format of the private key, detected from its content by default.
+    #[clap(long, possible_values = &["pem", "der", "jwk", "pkcs12"])]
+    key_format: Option<KeyFormat>,
+    /// The filename of the passphrase of an encrypted private key or PKCS#12 bundle.
     #[clap(long)]
-    key: PathBuf,
+    passphrase_file: Option<PathBuf>,
+    /// The filename of the PKCS#11 module of the token holding the private key, e.g.
+    /// `/usr/lib/softhsm/libsofthsm2.so`, to sign on the token instead of with `--key`.
+    /// The PIN of the token is read from the `TLSIGN_PKCS11_PIN` environment variable or,
+    /// failing that, prompted.
+    #[clap(
+        long,
+        conflicts_with = "key",
+        requires_all = &["token-label", "key-label"]
+    )]
+    pkcs11_module: Option<PathBuf>,
+    /// The label of the PKCS#11 token holding the private key.
+    #[clap(long, requires = "pkcs11-module")]
+    token_label: Option<String>,
+    /// The label of the private key on the PKCS#11 token.
+    #[clap(long, requires = "pkcs11-module")]
+    key_label: Option<String>,
+    /// The socket of a `tlsign agent` holding the private key of `--kid`, e.g.
+    /// `$TLSIGN_AUTH_SOCK`, to sign with the agent instead of with `--key`.
+    #[clap(long, conflicts_with_all = &["key", "pkcs11-module"])]
+    agent: Option<PathBuf>,
     /// The certificate id associated to the public certificate you uploaded in TrueLayer's Console.
     /// The certificate id can be retrieved in the Payouts Setting section.
     /// It will be used as the `kid` header in the JWS.
+    /// Defaults to the `kid` of the profile.
+    #[clap(long)]
+    kid: Option<Uuid>,
+    /// The filename of the certificate uploaded in TrueLayer's Console for `--kid`, in PEM format.
+    /// Signing fails if its public key does not match the private key, rather than the API
+    /// rejecting the signature. Defaults to the `cert` of the profile when using its `kid`.
+    #[clap(long)]
+    cert: Option<PathBuf>,
+    /// The JWS algorithm to sign with, one of `ES256`, `ES384` or `ES512`.
+    /// Defaults to the `alg` of the profile, otherwise to the algorithm matching the curve of the
+    /// private key.
+    #[clap(long)]
+    alg: Option<Algorithm>,
+    /// Sign using deterministic ECDSA, as described in RFC6979, rather than a random nonce, so that
+    /// signing the same payload twice gives the same signature, e.g. for golden-file tests.
+    #[clap(long, conflicts_with_all = &["pkcs11-module", "agent"])]
+    deterministic: bool,
+    /// The profile of `~/.config/tlsign/config.toml` providing the defaults of the options,
+    /// e.g. `sandbox`. Defaults to the `default_profile` of the configuration file.
+    #[clap(long, env = "TLSIGN_PROFILE")]
+    profile: Option<String>,
+}
+
+impl KeyOptions {
+    /// The selected profile of the configuration file.
+    pub fn profile(&self) -> anyhow::Result<Profile> {
+        Config::load()?.profile(self.profile.as_deref())
+    }
+
+    /// The signer for the private key and `kid`, using the requested algorithm if any, falling
+    /// back to those of `profile`.
+    pub fn signer(&self, profile: &Profile) -> anyhow::Result<Signer> {
+        let kid = self
+            .kid
+            .or(profile.kid)
+            .context("Provide the `kid` with `--kid`, or set it in the profile.")?
+            .to_string();
+        let signer = match (&self.key, &self.pkcs11_module, &self.agent) {
+            (Some(key), _, _) => self.in_memory_signer(
+                read_private_key(key, self.key_format, self.passphrase_file.as_deref())?,
+                kid,
+            )?,
+            (None, Some(module), _) => self.pkcs11_signer(module, kid)?,
+            (None, None, Some(agent)) => self.agent_signer(agent, kid)?,
+            (None, None, None) => {
+                let key = profile.key.as_deref().context(
+                    "Provide the private key with `--key`, `--pkcs11-module` or `--agent`, \
+                     or set `key` in the profile.",
+                )?;
+                let passphrase_file = self
+                    .passphrase_file
+                    .as_deref()
+                    .or(profile.passphrase_file.as_deref());
+                self.in_memory_signer(
+                    read_private_key(key, self.key_format.or(profile.key_format), passphrase_file)?,
+                    kid,
+                )?
+            }
+        };
+        let profile_cert = match self.kid {
+            Some(kid) if profile.kid != Some(kid) => None,
+            _ => profile.cert.as_deref(),
+        };
+        if let Some(cert) = self.cert.as_deref().or(profile_cert) {
+            let raw_cert = std::fs::read(cert).context("Failed to read the certificate file.")?;
+            signer
+                .check_public_key(&*public_key_from_pem(&raw_cert)?)
+                .with_context(|| {
+                    format!(
+                        "Failed to check the private key against {}.",
+                        cert.display()
+                    )
+                })?;
+        }
+        match self.alg.or(profile.alg) {
+            Some(alg) => signer.with_algorithm(alg),
+            None => Ok(signer),
+        }
+    }
+}
+
+impl KeyOptions {
+    fn in_memory_signer(&self, key: EcKey<Private>, kid: String) -> anyhow::Result<Signer> {
+        if self.deterministic {
+            Signer::new(DeterministicKey::new(key), kid)
+        } else {
+            Signer::new(key, kid)
+        }
+    }
+
+    #[cfg(unix)]
+    fn pkcs11_signer(&self, module: &Path, kid: String) -> anyhow::Result<Signer> {
+        let token_label = self.token_label.as_deref().unwrap_or_default();
+        let pin = passphrase::read_pin(token_label)?;
+        let key = Pkcs11Key::open(
+            module,
+            token_label,
+            self.key_label.as_deref().unwrap_or_default(),
+            &pin,
+        )?;
+        Signer::new(key, kid)
+    }
+
+    #[cfg(not(unix))]
+    fn pkcs11_signer(&self, _module: &Path, _kid: String) -> anyhow::Result<Signer> {
+        Err(anyhow::anyhow!(
+            "Signing with a PKCS#11 token is only supported on unix."
+        ))
+    }
+
+    #[cfg(unix)]
+    fn agent_signer(&self, socket: &Path, kid: String) -> anyhow::Result<Signer> {
+        let key = AgentClient::new(socket).key(&kid)?;
+        Signer::new(key, kid)
+    }
+
+    #[cfg(not(unix))]
+    fn agent_signer(&self, _socket: &Path, _kid: String) -> anyhow::Result<Signer> {
+        Err(anyhow::anyhow!(
+            "Signing with an agent is only supported on unix."
+        ))
+    }
+}
+
+/// Parse the EC private key from the specified file, in the given format or the one detected from
+/// its content, obtaining its passphrase if it is encrypted.
+fn read_private_key(
+    path: &Path,
+    format: Option<KeyFormat>,
+    passphrase_file: Option<&Path>,
+) -> anyhow::Result<EcKey<Private>> {
+    let raw_private_key = std::fs::read(path).context("Failed to read the private key file.")?;
+    match format.unwrap_or_else(|| KeyFormat::detect(&raw_private_key)) {
+        KeyFormat::Pem if is_encrypted_pem(&raw_private_key) => {
+            let passphrase = passphrase::read(path, passphrase_file)?;
+            private_key_from_pem_passphrase(&raw_private_key, passphrase.as_bytes())
+        }
+        KeyFormat::Pem => private_key_from_pem(&raw_private_key),
+        KeyFormat::Der => private_key_from_der(&raw_private_key),
+        KeyFormat::Jwk => private_key_from_jwk(&raw_private_key),
+        KeyFormat::Pkcs12 => {
+            let password = passphrase::read(path, passphrase_file)?;
+            private_key_from_pkcs12(&raw_private_key, &password)
+        }
+    }
+}
+
+/// Which signature the commands sending requests produce.
+#[derive(Clap)]
+struct SigningOptions {
+    /// Sign the body alone, sent as the `X-TL-Signature` header as expected by the
+    /// Payouts/Paydirect API, rather than the request using request signing v2.
+    /// The default when the profile sets `signing = "legacy"`.
     #[clap(long)]
-    kid: Uuid,
+    legacy: bool,
 }
 
-impl Command {
-    /// Parse the EC private key from the specified file.
-    pub fn private_key(&self) -> anyhow::Result<EcKey<Private>> {
-        let raw_private_key =
-            std::fs::read(&self.key).context("Failed to read the private key file.")?;
-        let private_key = openssl::pkey::PKey::private_key_from_pem(&raw_private_key)
-            .context("Failed to parse the private key as PEM.")?
-            .ec_key()
-            .context("The private key must be an Elliptic Curve key.")?;
-        private_key.check_key().context("Key verification failed")?;
-        Ok(private_key)
+impl SigningOptions {
+    /// Whether to produce a legacy signature, either requested or set by the profile.
+    pub fn is_legacy(&self, profile: &Profile) -> bool {
+        self.legacy || profile.signing == Some(Signing::Legacy)
+    }
+}
+
+/// How to rewrite the payload before signing it, only offered by the commands sending it, as
+/// verifying a rewritten payload would not check the bytes actually received.
+#[derive(Clap)]
+struct RewriteOptions {
+    /// Canonicalize the JSON payload as described in RFC8785 (JCS) before signing it, so that it
+    /// can be re-serialized, e.g. by an HTTP library, without breaking the signature.
+    #[clap(long, conflicts_with = "minify")]
+    canonicalize: bool,
+    /// Remove the whitespace between the tokens of the JSON payload before signing it.
+    #[clap(long)]
+    minify: bool,
+}
+
+impl RewriteOptions {
+    /// The payload canonicalized or minified if requested.
+    pub fn apply(&self, body: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        if self.canonicalize {
+            canonicalize_json(&body).context("Failed to canonicalize the payload.")
+        } else if self.minify {
+            minify_json(&body).context("Failed to minify the payload.")
+        } else {
+            Ok(body)
+        }
+    }
+
+    /// Whether the payload is rewritten, and differs from the one provided.
+    pub fn is_rewritten(&self) -> bool {
+        self.canonicalize || self.minify
+    }
+}
+
+/// Where to read the payload from. The payload is used byte for byte, so prefer `--body-file`
+/// or stdin for large payloads or ones that are awkward to quote in a shell.
+#[derive(Clap)]
+struct BodyOptions {
+    /// The payload. Use `-` to read it from stdin.
+    #[clap(
+        long,
+        required_unless_present = "body-file",
+        conflicts_with = "body-file"
+    )]
+    body: Option<String>,
+    /// The filename of the payload.
+    #[clap(long)]
+    body_file: Option<PathBuf>,
+}
+
+impl BodyOptions {
+    /// Read the exact bytes of the payload.
+    pub fn read(&self) -> anyhow::Result<Vec<u8>> {
+        match (&self.body, &self.body_file) {
+            (Some(body), _) if body == "-" => {
+                let mut body = Vec::new();
+                std::io::stdin()
+                    .read_to_end(&mut body)
+                    .context("Failed to read the payload from stdin.")?;
+                Ok(body)
+            }
+            (Some(body), _) => Ok(body.as_bytes().to_vec()),
+            (None, Some(body_file)) => {
+                std::fs::read(body_file).context("Failed to read the payload file.")
+            }
+            (None, None) => Err(anyhow::anyhow!(
+                "The payload must be provided with either `--body` or `--body-file`."
+            )),
+        }
+    }
+}
+
+/// Options to sign the HTTP request using TrueLayer's request signing v2, sent as the
+/// `Tl-Signature` header, rather than the payload alone.
+#[derive(Clap)]
+struct RequestOptions {
+    /// The HTTP method of the request, e.g. `POST`.
+    /// When set, the request is signed using request signing v2 and `--path` is required.
+    #[clap(long, requires = "path")]
+    method: Option<String>,
+    /// The path of the request, e.g. `/v3/payments`.
+    #[clap(long, requires = "method")]
+    path: Option<String>,
+    /// A header to include in the signature, as `name:value`, e.g. `Idempotency-Key:1234`.
+    /// Can be repeated; headers are signed in the order they are given.
+    #[clap(
+        long = "header",
+        value_name = "name:value",
+        number_of_values = 1,
+        requires = "method"
+    )]
+    headers: Vec<Header>,
+}
+
+#[derive(Clap)]
+struct Keygen {
+    /// The JWS algorithm the key will sign with, one of `ES256`, `ES384` or `ES512`.
+    #[clap(long, default_value = "ES512")]
+    alg: Algorithm,
+    /// The directory to write the private key, public key and certificate to.
+    /// It is created if missing; existing files are never overwritten.
+    #[clap(long)]
+    out: PathBuf,
+    /// The common name of the subject of the self-signed certificate.
+    #[clap(long, default_value = "tlsign")]
+    common_name: String,
+    /// The number of days the self-signed certificate is valid for.
+    #[clap(long, default_value = "365")]
+    days: u32,
+}
+
+#[derive(Clap)]
+struct Send {
+    /// The URL to send the request to, e.g. `https://api.truelayer-sandbox.com/v3/payments`.
+    /// `http://` URLs are accepted too, e.g. to test against a local server.
+    /// A path, e.g. `/v3/payments`, is appended to the `url` of the profile.
+    #[clap(long)]
+    url: String,
+    /// The HTTP method of the request.
+    #[clap(long, default_value = "POST")]
+    method: String,
+    /// The access token sent as the `Authorization: Bearer` header.
+    #[clap(long)]
+    token: Option<String>,
+    /// The `Idempotency-Key` header. Defaults to a random UUID.
+    #[clap(long)]
+    idempotency_key: Option<String>,
+    /// An additional header to send and include in the signature, as `name:value`.
+    /// Can be repeated.
+    #[clap(long = "header", value_name = "name:value", number_of_values = 1)]
+    headers: Vec<Header>,
+    #[clap(flatten)]
+    signing: SigningOptions,
+    #[clap(flatten)]
+    body: BodyOptions,
+    #[clap(flatten)]
+    rewrite: RewriteOptions,
+    #[clap(flatten)]
+    key: KeyOptions,
+}
+
+#[derive(Clap)]
+struct Proxy {
+    /// The address to listen on for plain HTTP requests.
+    #[clap(long, default_value = "127.0.0.1:8080")]
+    listen: String,
+    /// The URL requests are forwarded to, e.g. `https://api.truelayer-sandbox.com`, their path
+    /// being appended to it. Defaults to the `url` of the profile.
+    #[clap(long)]
+    upstream: Option<http::Url>,
+    /// The name of a request header to include in the signature, besides `Idempotency-Key`.
+    /// Can be repeated.
+    #[clap(long = "sign-header", value_name = "name", number_of_values = 1)]
+    signed_headers: Vec<String>,
+    #[clap(flatten)]
+    signing: SigningOptions,
+    #[clap(flatten)]
+    key: KeyOptions,
+}
+
+#[derive(Clap)]
+struct Batch {
+    /// The JSON Lines file of requests, each line an object with `method`, `path`, `headers` and
+    /// `body`. Defaults to stdin.
+    #[clap(long)]
+    input: Option<PathBuf>,
+    /// The JSON Lines file to write the signed requests to. Defaults to stdout.
+    #[clap(long)]
+    output: Option<PathBuf>,
+    #[clap(flatten)]
+    signing: SigningOptions,
+    #[clap(flatten)]
+    key: KeyOptions,
+}
+
+/// A line of the input of `batch`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BatchRequest {
+    method: String,
+    path: String,
+    /// The headers to send and sign, in order.
+    #[serde(default)]
+    headers: serde_json::Map<String, Value>,
+    /// The body, signed as is if a string, or as compact JSON otherwise.
+    #[serde(default)]
+    body: Value,
+}
+
+#[derive(Clap)]
+struct Jwk {
+    #[clap(flatten)]
+    keys: KeyListOptions,
+    /// Print a JWKS, i.e. `{"keys": [...]}`, holding the JWK of every key.
+    #[clap(long)]
+    jwks: bool,
+}
+
+/// Several private keys, each identified by its `kid`.
+#[derive(Clap)]
+struct KeyListOptions {
+    /// The filename of an Elliptic Curve private key, in PEM, DER, JWK or PKCS#12 format.
+    /// Can be repeated, each key being identified by the `--kid` at the same position.
+    #[clap(
+        long = "key",
+        value_name = "key",
+        required = true,
+        number_of_values = 1
+    )]
+    keys: Vec<PathBuf>,
+    /// The certificate id associated to the public certificate uploaded in TrueLayer's Console for
+    /// the `--key` at the same position.
+    #[clap(
+        long = "kid",
+        value_name = "kid",
+        required = true,
+        number_of_values = 1
+    )]
+    kids: Vec<Uuid>,
+    /// The format of the private keys, detected from their content by default.
+    #[clap(long, possible_values = &["pem", "der", "jwk", "pkcs12"])]
+    key_format: Option<KeyFormat>,
+    /// The filename of the passphrase of encrypted private keys or PKCS#12 bundles.
+    #[clap(long)]
+    passphrase_file: Option<PathBuf>,
+}
+
+impl KeyListOptions {
+    /// Read the private keys, each with its `kid`.
+    pub fn read(&self) -> anyhow::Result<Vec<(EcKey<Private>, Uuid)>> {
+        if self.keys.len() != self.kids.len() {
+            return Err(anyhow::anyhow!(
+                "Every `--key` must have its `--kid`, found {} keys and {} kids.",
+                self.keys.len(),
+                self.kids.len()
+            ));
+        }
+        self.keys
+            .iter()
+            .zip(&self.kids)
+            .map(|(key, kid)| {
+                let private_key =
+                    read_private_key(key, self.key_format, self.passphrase_file.as_deref())
+                        .with_context(|| format!("Failed to load `{}`.", key.display()))?;
+                Ok((private_key, *kid))
+            })
+            .collect()
+    }
+}
+
+#[derive(Clap)]
+struct VerifyWebhook {
+    /// The filename of the JWKS the `jku` of the signature points to, e.g. downloaded from
+    /// `https://webhooks.truelayer.com/.well-known/jwks`.
+    #[clap(long)]
+    jwks: PathBuf,
+    /// The `Tl-Signature` header of the webhook.
+    #[clap(long)]
+    signature: String,
+    /// The HTTP method of the webhook.
+    #[clap(long, default_value = "POST")]
+    method: String,
+    /// The path the webhook was sent to, e.g. `/hook`.
+    #[clap(long)]
+    path: String,
+    /// A header of the webhook, as `name:value`. Can be repeated.
+    /// All the headers listed by the `tl_headers` of the signature must be provided.
+    #[clap(long = "header", value_name = "name:value", number_of_values = 1)]
+    headers: Vec<Header>,
+    #[clap(flatten)]
+    body: BodyOptions,
+    /// A `jku` to accept, instead of TrueLayer's production and sandbox ones. Can be repeated.
+    #[clap(long = "allow-jku", value_name = "jku", number_of_values = 1)]
+    allowed_jkus: Vec<String>,
+}
+
+#[derive(Clap)]
+struct Inspect {
+    /// The JWS, either compact or with detached payload, e.g. the value of the `Tl-Signature`
+    /// header. Use `-` to read it from stdin.
+    jws: String,
+}
+
+#[cfg(unix)]
+#[derive(Clap)]
+struct Agent {
+    #[clap(flatten)]
+    socket: AgentSocket,
+    #[clap(flatten)]
+    keys: KeyListOptions,
+    /// How many seconds the keys are held for, after which they are forgotten and the agent stops.
+    /// By default, they are held until the agent is stopped.
+    #[clap(long, value_name = "seconds")]
+    lifetime: Option<u64>,
+}
+
+/// The socket of the agent.
+#[cfg(unix)]
+#[derive(Clap)]
+struct AgentSocket {
+    /// The path of the Unix socket of the agent.
+    #[clap(long, env = "TLSIGN_AUTH_SOCK")]
+    socket: PathBuf,
+}
+
+#[cfg(unix)]
+#[derive(Clap)]
+struct AgentPassphrase {
+    #[clap(flatten)]
+    socket: AgentSocket,
+    /// The filename of the passphrase locking the agent, prompted for by default.
+    #[clap(long)]
+    passphrase_file: Option<PathBuf>,
+}
+
+#[derive(Clap)]
+struct Verify {
+    /// The JWS with detached payload to verify, e.g. the value of the `X-TL-Signature` header.
+    #[clap(long)]
+    jws: String,
+    #[clap(flatten)]
+    body: BodyOptions,
+    /// The filename of the public certificate uploaded in TrueLayer's Console, in PEM format.
+    /// A bare Elliptic Curve public key in PEM format is also accepted.
+    #[clap(long)]
+    cert: PathBuf,
+}
+
+impl Verify {
+    /// Parse the EC public key from the specified certificate file.
+    pub fn public_key(&self) -> anyhow::Result<EcKey<Public>> {
+        let raw_cert = std::fs::read(&self.cert).context("Failed to read the certificate file.")?;
+        public_key_from_pem(&raw_cert)
     }
 }
 
@@ -43,77 +667,857 @@ pub struct JwsPayload {
     body: Value,
 }
 
+/// The command line arguments, with `sign` inserted when they start with an option rather than a
+/// subcommand, so that `tlsign --body … --key … --kid …` from before the subcommands still signs.
+fn args() -> Vec<OsString> {
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    let is_sign_option = args.get(1).and_then(|arg| arg.to_str()).is_some_and(|arg| {
+        arg.starts_with('-') && !["-h", "--help", "-V", "--version"].contains(&arg)
+    });
+    if is_sign_option {
+        args.insert(1, "sign".into());
+    }
+    args
+}
+
 pub fn main() -> anyhow::Result<()> {
-    let options = Command::parse();
+    match Command::parse_from(args()) {
+        Command::Sign(options) => sign(options),
+        Command::Verify(options) => verify(options),
+        Command::Keygen(options) => keygen(options),
+        Command::Send(options) => send(options),
+        Command::Jwk(options) => jwk(options),
+        Command::VerifyWebhook(options) => verify_webhook(options),
+        Command::Inspect(options) => {
+            let jws = if options.jws == "-" {
+                let mut jws = String::new();
+                std::io::stdin()
+                    .read_to_string(&mut jws)
+                    .context("Failed to read the JWS from stdin.")?;
+                jws
+            } else {
+                options.jws
+            };
+            println!("{}", inspect::inspect(&jws)?);
+            Ok(())
+        }
+        Command::Proxy(options) => proxy(options),
+        Command::Batch(options) => batch(options),
+        #[cfg(unix)]
+        Command::Agent(options) => agent(options),
+        #[cfg(unix)]
+        Command::AgentKeys(options) => agent_keys(options),
+        #[cfg(unix)]
+        Command::AgentLock(options) => AgentClient::new(options.socket.socket)
+            .lock(&passphrase::read_agent(options.passphrase_file.as_deref())?),
+        #[cfg(unix)]
+        Command::AgentUnlock(options) => AgentClient::new(options.socket.socket)
+            .unlock(&passphrase::read_agent(options.passphrase_file.as_deref())?),
+    }
+}
 
-    let jws_header = json!({
-        "alg": "ES512",
-        "kid": options.kid.to_string()
-    });
-    let private_key = options.private_key()?;
-    // println!("Request payload:\n{}\n", &jws_payload);
+fn sign(options: Sign) -> anyhow::Result<()> {
+    let profile = options.key.profile()?;
+    let mut signer = options.key.signer(&profile)?;
+    if options.unencoded_payload {
+        signer = signer.with_unencoded_payload();
+    }
+    let body = options.rewrite.apply(options.body.read()?)?;
+    let (jws, signature_header) = match &options.request {
+        RequestOptions {
+            method: Some(method),
+            path: Some(path),
+            headers,
+        } => (
+            signer.sign_request(method, path, headers, &body)?,
+            "Tl-Signature",
+        ),
+        _ => (signer.sign(&body)?, "X-TL-Signature"),
+    };
 
-    let jws = get_jws(&jws_header, options.body.as_bytes(), private_key)?;
-    // println!("JWS:\n{}\n", jws);
+    match options.emit {
+        Emit::Jws => {
+            println!("{}", jws);
+            // The payload to send is no longer the one provided
+            if options.rewrite.is_rewritten() {
+                std::io::stdout().write_all(&body)?;
+                println!();
+            }
+        }
+        Emit::Json => {
+            let jws_payload = match &options.request {
+                RequestOptions {
+                    method: Some(method),
+                    path: Some(path),
+                    headers,
+                } => v2_signing_payload(method, path, headers, &body),
+                _ => body.clone(),
+            };
+            let output = describe_signature(&signer, &jws, signature_header, &jws_payload, &body)?;
+            println!("{}", serde_json::to_string_pretty(&output)?);
+        }
+        Emit::Curl => {
+            let url = options
+                .url
+                .or_else(|| profile.url.as_ref().map(ToString::to_string))
+                .context("`--url` is required with `--emit curl`, unless set in the profile.")?;
+            let url = curl_url(&url, options.request.path.as_deref())?;
+            let mut headers = options.request.headers.clone();
+            if let Some(token) = &options.token {
+                headers.push(Header::new("Authorization", format!("Bearer {}", token)));
+            }
+            if !headers
+                .iter()
+                .any(|header| header.name().eq_ignore_ascii_case("Content-Type"))
+            {
+                headers.push(Header::new("Content-Type", "application/json"));
+            }
+            headers.push(Header::new(signature_header, jws.to_string()));
 
-    let parts = jws.split(".").collect::<Vec<_>>();
-    let detached_jsw = format!("{}..{}", parts[0], parts[2]);
-    // Omit the payload for a JWS with detached payload
-    println!("{}", detached_jsw);
+            let body = curl_body(body, options.body.body_file.as_deref())?;
+            let method = options.request.method.as_deref().unwrap_or("POST");
+            println!("{}", curl_command(method, &url, &headers, body));
+        }
+    }
 
     Ok(())
 }
 
-/// Get a JWS using the ES512 signing scheme.
-///
-/// Check section A.4 of RFC7515 for the details: https://www.rfc-editor.org/rfc/rfc7515.txt
-pub fn get_jws(
-    jws_header: &Value,
+/// The description of a signature printed by `--emit json`, `jws_payload` being the payload
+/// signed for `body`.
+fn describe_signature(
+    signer: &Signer,
+    jws: &DetachedJws,
+    signature_header: &str,
     jws_payload: &[u8],
-    pkey: EcKey<Private>,
-) -> Result<String, anyhow::Error> {
-    let to_be_signed = format!(
-        "{}.{}",
-        base64_encode(serde_json::to_string(&jws_header)?.as_bytes()),
-        base64_encode(jws_payload),
+    body: &[u8],
+) -> anyhow::Result<Value> {
+    let body_sha256: String = openssl::sha::sha256(body)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    Ok(json!({
+        "jws": jws.to_string(),
+        "signature_header": signature_header,
+        // An unencoded payload with a `.` can only be detached
+        "attached_jws": jws.attach(jws_payload).ok(),
+        "header": jws.header()?,
+        "signing_input": String::from_utf8(jws.signing_input(jws_payload)?).ok(),
+        "alg": signer.alg().name(),
+        "kid": signer.kid(),
+        "jwk_thumbprint": jwk_thumbprint(&*signer.public_key()?)?,
+        "body_sha256": body_sha256,
+        "body": std::str::from_utf8(body).ok(),
+    }))
+}
+
+/// The body of the request sent by curl.
+enum CurlBody<'a> {
+    Inline(String),
+    File(&'a Path),
+}
+
+/// The URL curl sends the request to: `url`, followed by the signed `path` if any.
+fn curl_url(url: &str, path: Option<&str>) -> anyhow::Result<String> {
+    match path {
+        // The signed path must be the one sent, so it cannot come after a query string
+        Some(_) if url.contains('?') => Err(anyhow::anyhow!(
+            "`--url` cannot have a query string with `--path`, add it to `--path` instead."
+        )),
+        Some(path) => Ok(url.parse::<http::Url>()?.join(path).to_string()),
+        None => Ok(url.to_owned()),
+    }
+}
+
+/// The body curl sends: the payload itself when it is valid UTF-8, or else the file it was read
+/// from, as a shell argument cannot hold any byte.
+fn curl_body(body: Vec<u8>, body_file: Option<&Path>) -> anyhow::Result<CurlBody<'_>> {
+    match (String::from_utf8(body), body_file) {
+        (Ok(body), _) => Ok(CurlBody::Inline(body)),
+        (Err(_), Some(body_file)) => Ok(CurlBody::File(body_file)),
+        (Err(_), None) => Err(anyhow::anyhow!(
+            "A payload that is not valid UTF-8 can only be sent by curl from a file, use \
+             `--body-file`."
+        )),
+    }
+}
+
+/// A curl command sending a request, each argument quoted for POSIX shells.
+fn curl_command(method: &str, url: &str, headers: &[Header], body: CurlBody<'_>) -> String {
+    let mut command = format!(
+        "curl -X {} {}",
+        shell_quote(&method.to_uppercase()),
+        shell_quote(url)
     );
-    let signature = sign_es512(to_be_signed.as_bytes(), pkey)?;
+    for header in headers {
+        let header = format!("{}: {}", header.name(), header.value());
+        command.push_str(&format!(" \\\n  -H {}", shell_quote(&header)));
+    }
+    match body {
+        CurlBody::Inline(body) if body.is_empty() => {}
+        // Unlike `--data-binary`, `--data-raw` does not read a file for a payload starting with `@`
+        CurlBody::Inline(body) => {
+            command.push_str(&format!(" \\\n  --data-raw {}", shell_quote(&body)))
+        }
+        CurlBody::File(path) => command.push_str(&format!(
+            " \\\n  --data-binary {}",
+            shell_quote(&format!("@{}", path.display()))
+        )),
+    }
+    command
+}
+
+/// Quote a shell argument, leaving it as is if it is only made of safe characters.
+fn shell_quote(arg: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "-_./:=,+%".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        arg.to_owned()
+    } else {
+        // Single quotes preserve everything but themselves, which are closed, escaped and reopened
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+fn send(options: Send) -> anyhow::Result<()> {
+    let profile = options.key.profile()?;
+    let signer = options.key.signer(&profile)?;
+    let url = if options.url.starts_with('/') {
+        profile
+            .url
+            .as_ref()
+            .context("`--url` must be a full URL, unless the profile sets the base `url`.")?
+            .join(&options.url)
+    } else {
+        options.url.parse()?
+    };
+    let body = options.rewrite.apply(options.body.read()?)?;
+    let method = options.method.to_uppercase();
+
+    let idempotency_key = match options.idempotency_key {
+        Some(idempotency_key) => idempotency_key,
+        None => random_uuid()?.to_string(),
+    };
+    let mut signed_headers = vec![Header::new("Idempotency-Key", idempotency_key)];
+    signed_headers.extend(options.headers);
+    let signature = if options.signing.is_legacy(&profile) {
+        Header::new("X-TL-Signature", signer.sign(&body)?.to_string())
+    } else {
+        let jws = signer.sign_request(&method, url.path(), &signed_headers, &body)?;
+        Header::new("Tl-Signature", jws.to_string())
+    };
 
-    let jws = format!(
-        "{}.{}.{}",
-        base64_encode(serde_json::to_string(&jws_header)?.as_bytes()),
-        base64_encode(jws_payload),
-        signature
+    let mut headers = signed_headers;
+    if !headers
+        .iter()
+        .any(|header| header.name().eq_ignore_ascii_case("Content-Type"))
+    {
+        headers.push(Header::new("Content-Type", "application/json"));
+    }
+    if let Some(token) = &options.token {
+        headers.push(Header::new("Authorization", format!("Bearer {}", token)));
+    }
+    headers.push(signature);
+
+    let response = http::send(&url, &method, &headers, &body)?;
+    println!("{} {}", response.status, response.reason);
+    for header in &response.headers {
+        println!("{}: {}", header.name(), header.value());
+    }
+    println!();
+    match serde_json::from_slice::<Value>(&response.body) {
+        Ok(json) => println!("{}", serde_json::to_string_pretty(&json)?),
+        Err(_) => println!("{}", String::from_utf8_lossy(&response.body)),
+    }
+
+    if !response.is_success() {
+        return Err(anyhow::anyhow!(
+            "The request failed with status {}.",
+            response.status
+        ));
+    }
+    Ok(())
+}
+
+fn proxy(options: Proxy) -> anyhow::Result<()> {
+    let listener = std::net::TcpListener::bind(&options.listen)
+        .with_context(|| format!("Failed to listen on {}.", options.listen))?;
+    let profile = options.key.profile()?;
+    let proxy = proxy::Proxy {
+        upstream: options
+            .upstream
+            .or_else(|| profile.url.clone())
+            .context("Provide the URL to forward requests to with `--upstream`, or set `url` in the profile.")?,
+        signer: options.key.signer(&profile)?,
+        legacy: options.signing.is_legacy(&profile),
+        signed_headers: options.signed_headers,
+    };
+    eprintln!(
+        "Listening on http://{}, forwarding signed requests to {}.",
+        options.listen, proxy.upstream
     );
-    Ok(jws)
+    proxy.serve(listener)
+}
+
+fn batch(options: Batch) -> anyhow::Result<()> {
+    let profile = options.key.profile()?;
+    let signer = options.key.signer(&profile)?;
+    let legacy = options.signing.is_legacy(&profile);
+    let input: Box<dyn BufRead> = match &options.input {
+        Some(input) => Box::new(std::io::BufReader::new(
+            std::fs::File::open(input).context("Failed to open the input file.")?,
+        )),
+        None => Box::new(std::io::stdin().lock()),
+    };
+    let output: Box<dyn Write> = match &options.output {
+        Some(output) => {
+            Box::new(std::fs::File::create(output).context("Failed to create the output file.")?)
+        }
+        None => Box::new(std::io::stdout()),
+    };
+    let mut output = std::io::BufWriter::new(output);
+
+    for (i, line) in input.lines().enumerate() {
+        let line = line.context("Failed to read the input.")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid request on line {}.", i + 1))?;
+        let signed = sign_batch_request(&signer, request, legacy)
+            .with_context(|| format!("Failed to sign the request on line {}.", i + 1))?;
+        writeln!(output, "{}", signed).context("Failed to write the output.")?;
+    }
+    output.flush().context("Failed to write the output.")?;
+
+    Ok(())
+}
+
+/// Sign a request of the input of `batch`, adding an `Idempotency-Key` header unless present.
+fn sign_batch_request(
+    signer: &Signer,
+    request: BatchRequest,
+    legacy: bool,
+) -> anyhow::Result<Value> {
+    let method = request.method.to_uppercase();
+    let body = match request.body {
+        Value::String(body) => body,
+        Value::Null => String::new(),
+        body => body.to_string(),
+    };
+    let mut headers = request
+        .headers
+        .into_iter()
+        .map(|(name, value)| match value {
+            Value::String(value) => Ok(Header::new(name, value)),
+            _ => Err(anyhow::anyhow!(
+                "The value of the header `{}` must be a string.",
+                name
+            )),
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let idempotency_key = match http::header_value(&headers, "Idempotency-Key") {
+        Some(idempotency_key) => idempotency_key.to_owned(),
+        None => {
+            let idempotency_key = random_uuid()?.to_string();
+            headers.insert(0, Header::new("Idempotency-Key", idempotency_key.clone()));
+            idempotency_key
+        }
+    };
+
+    let signature = if legacy {
+        signer.sign(body.as_bytes())?
+    } else {
+        signer.sign_request(&method, &request.path, &headers, body.as_bytes())?
+    };
+    Ok(json!({
+        "method": method,
+        "path": request.path,
+        "headers": headers
+            .iter()
+            .map(|header| (header.name().to_owned(), json!(header.value())))
+            .collect::<serde_json::Map<_, _>>(),
+        "body": body,
+        "idempotency_key": idempotency_key,
+        "signature": signature.to_string(),
+    }))
+}
+
+/// A random (version 4) UUID.
+fn random_uuid() -> anyhow::Result<Uuid> {
+    let mut bytes = [0; 16];
+    openssl::rand::rand_bytes(&mut bytes)?;
+    Ok(uuid::Builder::from_bytes(bytes)
+        .set_variant(uuid::Variant::RFC4122)
+        .set_version(uuid::Version::Random)
+        .build())
 }
 
-/// Sign a payload using the provided private key and return the signature as a base64 encoded string.
-///
-/// Check section A.4 of RFC7515 for the details: https://www.rfc-editor.org/rfc/rfc7515.txt
-pub fn sign_es512(payload: &[u8], pkey: EcKey<Private>) -> Result<String, anyhow::Error> {
-    if pkey.group().curve_name() != Some(Nid::SECP521R1) {
+fn jwk(options: Jwk) -> anyhow::Result<()> {
+    if options.keys.keys.len() > 1 && !options.jwks {
         return Err(anyhow::anyhow!(
-            "The underlying elliptic curve must be P-521 to sign using ES512."
+            "Several keys can only be printed as a JWKS, use `--jwks`."
         ));
     }
-    let hash = openssl::hash::hash(MessageDigest::sha512(), &payload)?;
-    let structured_signature = EcdsaSig::sign(&hash, &pkey)?;
 
-    let r = structured_signature.r().to_vec();
-    let s = structured_signature.s().to_vec();
-    let mut signature_bytes: Vec<u8> = Vec::new();
-    // Padding to fixed length
-    signature_bytes.extend(std::iter::repeat(0x00).take(66 - r.len()));
-    signature_bytes.extend(r);
-    // Padding to fixed length
-    signature_bytes.extend(std::iter::repeat(0x00).take(66 - s.len()));
-    signature_bytes.extend(s);
+    let output = public_jwks(&options.keys.read()?, options.jwks)?;
+    println!("{}", serde_json::to_string_pretty(&output)?);
+
+    Ok(())
+}
+
+/// The JWKS of the public keys of `keys`, or the JWK of the first one unless `jwks`.
+fn public_jwks(keys: &[(EcKey<Private>, Uuid)], jwks: bool) -> anyhow::Result<Value> {
+    let mut keys = keys
+        .iter()
+        .map(|(private_key, kid)| public_jwk(private_key, &kid.to_string()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if jwks {
+        Ok(json!({ "keys": keys }))
+    } else {
+        Ok(keys.remove(0))
+    }
+}
+
+#[cfg(unix)]
+fn agent(options: Agent) -> anyhow::Result<()> {
+    let lifetime = options.lifetime.map(std::time::Duration::from_secs);
+    let mut agent = tlsign::Agent::default();
+    for (private_key, kid) in options.keys.read()? {
+        agent.add_key(kid.to_string(), private_key, lifetime)?;
+    }
+
+    let listener = tlsign::Agent::bind(&options.socket.socket)?;
+    eprintln!(
+        "Listening on {}, e.g. `export {}={}`.",
+        options.socket.socket.display(),
+        AUTH_SOCK_ENV,
+        options.socket.socket.display()
+    );
+    agent.serve(listener)
+}
+
+#[cfg(unix)]
+fn agent_keys(options: AgentSocket) -> anyhow::Result<()> {
+    let keys = AgentClient::new(options.socket).list()?;
+    if keys.is_empty() {
+        println!("The agent holds no keys.");
+    }
+    for key in keys {
+        match key.expires_in {
+            Some(expires_in) => println!("{} {} (expires in {}s)", key.kid, key.alg, expires_in),
+            None => println!("{} {}", key.kid, key.alg),
+        }
+    }
+
+    Ok(())
+}
+
+fn verify(options: Verify) -> anyhow::Result<()> {
+    let public_key = options.public_key()?;
+    let jws: DetachedJws = options.jws.parse()?;
+    jws.verify(&options.body.read()?, &public_key)?;
+    println!("Signature is valid.");
+
+    Ok(())
+}
+
+fn verify_webhook(options: VerifyWebhook) -> anyhow::Result<()> {
+    let jwks: Value = serde_json::from_slice(
+        &std::fs::read(&options.jwks).context("Failed to read the JWKS file.")?,
+    )
+    .context("The JWKS is not valid JSON.")?;
+    let mut verifier = WebhookVerifier::new(&jwks)?;
+    if !options.allowed_jkus.is_empty() {
+        verifier = verifier.with_allowed_jkus(options.allowed_jkus);
+    }
+
+    let signature: DetachedJws = options.signature.parse()?;
+    verifier.verify(
+        &signature,
+        &options.method.to_uppercase(),
+        &options.path,
+        &options.headers,
+        &options.body.read()?,
+    )?;
+    println!("Signature is valid.");
 
-    Ok(base64_encode(&signature_bytes))
+    Ok(())
 }
 
-/// Base64 encoding according to RFC7515 - see `Base64url` in section 2.
-pub fn base64_encode(payload: &[u8]) -> String {
-    base64::encode_config(payload, URL_SAFE_NO_PAD)
+fn keygen(options: Keygen) -> anyhow::Result<()> {
+    let private_key = generate_key(options.alg)?;
+    let certificate = self_signed_certificate(
+        &private_key,
+        options.alg,
+        &options.common_name,
+        options.days,
+    )?;
+
+    let prefix = match options.alg {
+        Algorithm::ES256 => "ec256",
+        Algorithm::ES384 => "ec384",
+        Algorithm::ES512 => "ec512",
+    };
+    std::fs::create_dir_all(&options.out).context("Failed to create the output directory.")?;
+    let private_key_path = options.out.join(format!("{}-private-key.pem", prefix));
+    let public_key_path = options.out.join(format!("{}-public-key.pem", prefix));
+    let certificate_path = options.out.join(format!("{}-certificate.pem", prefix));
+    for path in &[&private_key_path, &public_key_path, &certificate_path] {
+        if path.exists() {
+            return Err(anyhow::anyhow!(
+                "`{}` already exists, refusing to overwrite it.",
+                path.display()
+            ));
+        }
+    }
+    // Only the owner may read the private key
+    write_new_file(&private_key_path, &private_key.private_key_to_pem()?, 0o600)?;
+    write_new_file(&public_key_path, &private_key.public_key_to_pem()?, 0o644)?;
+    write_new_file(&certificate_path, &certificate.to_pem()?, 0o644)?;
+
+    println!("Private key: {}", private_key_path.display());
+    println!("Public key:  {}", public_key_path.display());
+    println!("Certificate: {}", certificate_path.display());
+    eprintln!("Upload the certificate in TrueLayer's Console to obtain the `kid` to sign with.");
+
+    Ok(())
+}
+
+/// Write a file that must not already exist, with the given unix permissions.
+fn write_new_file(path: &Path, contents: &[u8], mode: u32) -> anyhow::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+    #[cfg(not(unix))]
+    let _ = mode;
+
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(contents))
+        .with_context(|| format!("Failed to write `{}`.", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::pkey::Public;
+
+    /// The argument a POSIX shell passes for the quoted `arg`.
+    #[cfg(unix)]
+    fn unquote(arg: &str) -> String {
+        let output = std::process::Command::new("sh")
+            .args(["-c", &format!("printf %s {}", arg)])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn shell_quote_arguments() {
+        assert_eq!(shell_quote("https://x/v3/p"), "https://x/v3/p");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+        assert_eq!(shell_quote("`id`"), "'`id`'");
+        assert_eq!(shell_quote("@/etc/passwd"), "'@/etc/passwd'");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shell_quoted_arguments_are_passed_as_is() {
+        for arg in &[
+            "",
+            "'",
+            "it's",
+            "''\\'",
+            "$HOME ${HOME} $(id)",
+            "`id`",
+            "a; rm -rf / #",
+            "\"double\" \\ back\nslash",
+            "@/etc/passwd",
+            r#"{"name":"O'Brien"}"#,
+        ] {
+            assert_eq!(unquote(&shell_quote(arg)), *arg);
+        }
+    }
+
+    #[test]
+    fn curl_commands() {
+        let headers = [
+            Header::new("Idempotency-Key", "1"),
+            Header::new("Tl-Signature", "a..b"),
+        ];
+        assert_eq!(
+            curl_command(
+                "post",
+                "https://x/v3/p",
+                &headers,
+                CurlBody::Inline("@it's".to_owned())
+            ),
+            concat!(
+                "curl -X POST https://x/v3/p \\\n",
+                "  -H 'Idempotency-Key: 1' \\\n",
+                "  -H 'Tl-Signature: a..b' \\\n",
+                r"  --data-raw '@it'\''s'",
+            )
+        );
+        assert_eq!(
+            curl_command("GET", "https://x/", &[], CurlBody::Inline(String::new())),
+            "curl -X GET https://x/"
+        );
+        assert_eq!(
+            curl_command(
+                "POST",
+                "https://x/",
+                &[],
+                CurlBody::File(Path::new("my payload.bin"))
+            ),
+            "curl -X POST https://x/ \\\n  --data-binary '@my payload.bin'"
+        );
+    }
+
+    #[test]
+    fn curl_bodies() {
+        let file = Path::new("payload.bin");
+        assert!(matches!(
+            curl_body(b"{}".to_vec(), Some(file)).unwrap(),
+            CurlBody::Inline(body) if body == "{}"
+        ));
+        assert!(matches!(
+            curl_body(vec![0xff, 0xfe], Some(file)).unwrap(),
+            CurlBody::File(path) if path == file
+        ));
+        assert!(curl_body(vec![0xff, 0xfe], None).is_err());
+    }
+
+    /// A signer and the public key to verify its signatures.
+    fn signer() -> (Signer, EcKey<Public>) {
+        let key = tlsign::generate_key(Algorithm::ES512).unwrap();
+        let public_key = tlsign::SigningKey::public_key(&key).unwrap();
+        (
+            Signer::new(key, "45fc75cf-5649-4134-84b3-192c2c78e990").unwrap(),
+            public_key,
+        )
+    }
+
+    fn sign_batch_line(signer: &Signer, line: Value, legacy: bool) -> anyhow::Result<Value> {
+        sign_batch_request(signer, serde_json::from_value(line).unwrap(), legacy)
+    }
+
+    /// The headers of a line of the output of `batch`.
+    fn batch_headers(signed: &Value) -> Vec<Header> {
+        signed["headers"]
+            .as_object()
+            .unwrap()
+            .iter()
+            .map(|(name, value)| Header::new(name, value.as_str().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn sign_batch_requests() {
+        let (signer, public_key) = signer();
+        let signed = sign_batch_line(
+            &signer,
+            json!({
+                "method": "post",
+                "path": "/v3/payments",
+                "headers": {"idempotency-key": "1", "X-Custom": "a"},
+                "body": {"amount_in_minor": 100, "currency": "GBP"},
+            }),
+            false,
+        )
+        .unwrap();
+        // The existing `Idempotency-Key` is kept, whatever its case
+        assert_eq!(signed["idempotency_key"], "1");
+        assert_eq!(
+            signed["headers"],
+            json!({"idempotency-key": "1", "X-Custom": "a"})
+        );
+        assert_eq!(signed["method"], "POST");
+        // A JSON body is signed as compact JSON
+        assert_eq!(
+            signed["body"],
+            r#"{"amount_in_minor":100,"currency":"GBP"}"#
+        );
+
+        let jws: DetachedJws = signed["signature"].as_str().unwrap().parse().unwrap();
+        assert_eq!(
+            jws.header().unwrap()["tl_headers"],
+            "idempotency-key,X-Custom"
+        );
+        jws.verify_request(
+            "POST",
+            "/v3/payments",
+            &batch_headers(&signed),
+            signed["body"].as_str().unwrap().as_bytes(),
+            &public_key,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn sign_batch_requests_without_an_idempotency_key() {
+        let (signer, public_key) = signer();
+        // A string body is signed as is
+        let body = "{ \"amount_in_minor\": 100 }";
+        let signed = sign_batch_line(
+            &signer,
+            json!({"method": "POST", "path": "/v3/payments", "body": body}),
+            false,
+        )
+        .unwrap();
+        assert_eq!(signed["body"], body);
+        let idempotency_key = signed["idempotency_key"].as_str().unwrap();
+        assert!(idempotency_key.parse::<Uuid>().is_ok());
+        assert_eq!(
+            signed["headers"],
+            json!({ "Idempotency-Key": idempotency_key })
+        );
+
+        let jws: DetachedJws = signed["signature"].as_str().unwrap().parse().unwrap();
+        jws.verify_request(
+            "POST",
+            "/v3/payments",
+            &batch_headers(&signed),
+            body.as_bytes(),
+            &public_key,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn sign_batch_requests_with_legacy() {
+        let (signer, public_key) = signer();
+        let signed = sign_batch_line(
+            &signer,
+            json!({"method": "POST", "path": "/payouts", "body": {"a": 1}}),
+            true,
+        )
+        .unwrap();
+        let jws: DetachedJws = signed["signature"].as_str().unwrap().parse().unwrap();
+        assert!(jws.header().unwrap().get("tl_version").is_none());
+        jws.verify(br#"{"a":1}"#, &public_key).unwrap();
+    }
+
+    #[test]
+    fn reject_invalid_batch_requests() {
+        let (signer, _) = signer();
+        assert_eq!(
+            sign_batch_line(
+                &signer,
+                json!({"method": "POST", "path": "/", "headers": {"X-Count": 1}}),
+                false,
+            )
+            .unwrap_err()
+            .to_string(),
+            "The value of the header `X-Count` must be a string."
+        );
+        assert!(serde_json::from_value::<BatchRequest>(
+            json!({"method": "POST", "path": "/", "x": 1})
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn print_jwks() {
+        let kids = [random_uuid().unwrap(), random_uuid().unwrap()];
+        let keys = vec![
+            (tlsign::generate_key(Algorithm::ES256).unwrap(), kids[0]),
+            (tlsign::generate_key(Algorithm::ES512).unwrap(), kids[1]),
+        ];
+        let jwks = public_jwks(&keys, true).unwrap();
+        assert_eq!(jwks.as_object().unwrap().len(), 1);
+        let jwks = jwks["keys"].as_array().unwrap();
+        assert_eq!(jwks.len(), 2);
+        for (jwk, (key, kid)) in jwks.iter().zip(&keys) {
+            assert_eq!(jwk["kid"], kid.to_string());
+            assert_eq!(jwk["use"], "sig");
+            assert!(jwk.get("d").is_none());
+            assert_eq!(jwk, &public_jwk(key, &kid.to_string()).unwrap());
+        }
+        assert_eq!(jwks[1]["alg"], "ES512");
+
+        let jwk = public_jwks(&keys[..1], false).unwrap();
+        assert_eq!(jwk, jwks[0]);
+    }
+
+    #[test]
+    fn describe_signatures() {
+        let (signer, public_key) = signer();
+        let body = br#"{"amount_in_minor":100}"#;
+        let headers = [Header::new("Idempotency-Key", "1")];
+        let jws = signer
+            .sign_request("POST", "/v3/payments", &headers, body)
+            .unwrap();
+        let jws_payload = v2_signing_payload("POST", "/v3/payments", &headers, body);
+        let output = describe_signature(&signer, &jws, "Tl-Signature", &jws_payload, body).unwrap();
+
+        assert_eq!(output["jws"], jws.to_string());
+        assert_eq!(output["signature_header"], "Tl-Signature");
+        assert_eq!(output["header"], jws.header().unwrap());
+        assert_eq!(
+            output["signing_input"].as_str().unwrap().as_bytes(),
+            &jws.signing_input(&jws_payload).unwrap()[..]
+        );
+        assert_eq!(output["attached_jws"], jws.attach(&jws_payload).unwrap());
+        assert_eq!(output["alg"], "ES512");
+        assert_eq!(output["kid"], signer.kid());
+        assert_eq!(
+            output["jwk_thumbprint"],
+            jwk_thumbprint(&public_key).unwrap()
+        );
+        // `echo -n '{"amount_in_minor":100}' | sha256sum`
+        assert_eq!(
+            output["body_sha256"],
+            "d1e834f249024bca5f53ac496d5729ced67eafc7837771e85c8cf7fb1e07fb00"
+        );
+        assert_eq!(output["body"], r#"{"amount_in_minor":100}"#);
+    }
+
+    #[test]
+    fn describe_unencoded_signatures() {
+        let (signer, _) = signer();
+        let signer = signer.with_unencoded_payload();
+        let body = b"amount.100\xff";
+        let jws = signer.sign(body).unwrap();
+        let output = describe_signature(&signer, &jws, "X-TL-Signature", body, body).unwrap();
+
+        // A payload with a `.` cannot be attached unencoded, nor a non UTF-8 one shown
+        assert_eq!(output["attached_jws"], Value::Null);
+        assert_eq!(output["signing_input"], Value::Null);
+        assert_eq!(output["body"], Value::Null);
+        assert_eq!(output["header"]["b64"], false);
+        assert_eq!(
+            output["body_sha256"],
+            openssl::sha::sha256(body)
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>()
+        );
+
+        let body = b"amount.100";
+        let jws = signer.sign(body).unwrap();
+        let output = describe_signature(&signer, &jws, "X-TL-Signature", body, body).unwrap();
+        assert_eq!(output["attached_jws"], Value::Null);
+        assert_eq!(
+            output["signing_input"].as_str().unwrap().as_bytes(),
+            &jws.signing_input(body).unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn curl_urls() {
+        assert_eq!(
+            curl_url("https://x/y/", Some("/v3/p?a=b")).unwrap(),
+            "https://x/y/v3/p?a=b"
+        );
+        assert_eq!(
+            curl_url("https://x/y?a=b", None).unwrap(),
+            "https://x/y?a=b"
+        );
+        assert!(curl_url("https://x/y?a=b", Some("/v3/p")).is_err());
+    }
 }
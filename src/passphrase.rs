@@ -0,0 +1,107 @@
+//! Obtaining the passphrase of an encrypted private key or of the agent, or the PIN of a PKCS#11
+//! token.
+use anyhow::Context;
+use std::path::Path;
+
+/// The environment variable holding the passphrase of the private key.
+pub const PASSPHRASE_ENV: &str = "TLSIGN_PASSPHRASE";
+
+/// The environment variable holding the PIN of the PKCS#11 token.
+pub const PIN_ENV: &str = "TLSIGN_PKCS11_PIN";
+
+/// Read the passphrase of `key`, from `passphrase_file` if provided, otherwise from the
+/// `TLSIGN_PASSPHRASE` environment variable, otherwise by prompting on the terminal.
+pub fn read(key: &Path, passphrase_file: Option<&Path>) -> anyhow::Result<String> {
+    if let Some(passphrase_file) = passphrase_file {
+        return read_file(passphrase_file);
+    }
+    if let Some(passphrase) = std::env::var_os(PASSPHRASE_ENV) {
+        return passphrase
+            .into_string()
+            .map_err(|_| anyhow::anyhow!("`{}` must be valid UTF-8.", PASSPHRASE_ENV));
+    }
+    prompt(&format!("Passphrase for {}: ", key.display())).with_context(|| {
+        format!(
+            "The private key is encrypted: provide its passphrase with `--passphrase-file` or `{}`.",
+            PASSPHRASE_ENV
+        )
+    })
+}
+
+/// Read the passphrase locking the agent, from `passphrase_file` if provided, otherwise by
+/// prompting on the terminal.
+pub fn read_agent(passphrase_file: Option<&Path>) -> anyhow::Result<String> {
+    match passphrase_file {
+        Some(passphrase_file) => read_file(passphrase_file),
+        None => prompt("Agent passphrase: ")
+            .context("Provide the passphrase of the agent with `--passphrase-file`."),
+    }
+}
+
+fn read_file(passphrase_file: &Path) -> anyhow::Result<String> {
+    let passphrase =
+        std::fs::read_to_string(passphrase_file).context("Failed to read the passphrase file.")?;
+    // Editors usually end files with a new line, which is not part of the passphrase
+    let passphrase = passphrase.strip_suffix('\n').unwrap_or(&passphrase);
+    let passphrase = passphrase.strip_suffix('\r').unwrap_or(passphrase);
+    Ok(passphrase.to_owned())
+}
+
+/// Read the PIN of the PKCS#11 token labelled `token_label`, from the `TLSIGN_PKCS11_PIN`
+/// environment variable, otherwise by prompting on the terminal.
+pub fn read_pin(token_label: &str) -> anyhow::Result<String> {
+    if let Some(pin) = std::env::var_os(PIN_ENV) {
+        return pin
+            .into_string()
+            .map_err(|_| anyhow::anyhow!("`{}` must be valid UTF-8.", PIN_ENV));
+    }
+    prompt(&format!("PIN for token {}: ", token_label))
+        .with_context(|| format!("Provide the PIN of the PKCS#11 token with `{}`.", PIN_ENV))
+}
+
+/// Prompt on the terminal, without echoing what is typed.
+#[cfg(unix)]
+fn prompt(prompt: &str) -> anyhow::Result<String> {
+    use std::{
+        fs::OpenOptions,
+        io::{BufRead, BufReader, Write},
+        os::unix::io::AsRawFd,
+    };
+
+    let mut tty = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .context("There is no terminal to prompt on.")?;
+    let fd = tty.as_raw_fd();
+
+    let mut original = std::mem::MaybeUninit::uninit();
+    // Safety: `fd` is a valid file descriptor and `original` is initialised on success
+    if unsafe { libc::tcgetattr(fd, original.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let original = unsafe { original.assume_init() };
+    let mut silent = original;
+    silent.c_lflag &= !libc::ECHO;
+    // Still echo the new line, so that what is printed next starts on its own line
+    silent.c_lflag |= libc::ECHONL;
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &silent) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let mut passphrase = String::new();
+    let read = tty
+        .write_all(prompt.as_bytes())
+        .and_then(|_| tty.flush())
+        .and_then(|_| BufReader::new(&tty).read_line(&mut passphrase));
+    // Always restore echoing, even if reading failed
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &original) };
+    read?;
+
+    Ok(passphrase.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+#[cfg(not(unix))]
+fn prompt(_prompt: &str) -> anyhow::Result<String> {
+    Err(anyhow::anyhow!("Prompting is only supported on unix."))
+}
@@ -0,0 +1,576 @@
+//! Signing with a private key held by a PKCS#11 token, e.g. an HSM, which never leaves it.
+//!
+//! The PKCS#11 module is loaded at runtime, so that no vendor library is needed to build.
+use crate::{Algorithm, SigningKey};
+use anyhow::Context;
+use openssl::{
+    bn::BigNumContext,
+    ec::{EcGroup, EcKey, EcPoint},
+    pkey::Public,
+};
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    ffi::{c_void, CStr, CString},
+    os::{raw::c_ulong, unix::ffi::OsStrExt},
+    path::Path,
+    ptr,
+    sync::{Mutex, MutexGuard},
+};
+
+type CkRv = c_ulong;
+type CkSlotId = c_ulong;
+type CkSessionHandle = c_ulong;
+type CkObjectHandle = c_ulong;
+
+const CKR_OK: CkRv = 0;
+const CKR_USER_ALREADY_LOGGED_IN: CkRv = 0x100;
+const CKR_CRYPTOKI_ALREADY_INITIALIZED: CkRv = 0x191;
+
+const CKF_SERIAL_SESSION: c_ulong = 0x4;
+const CKU_USER: c_ulong = 1;
+const CKO_PUBLIC_KEY: c_ulong = 2;
+const CKO_PRIVATE_KEY: c_ulong = 3;
+const CKK_EC: c_ulong = 3;
+const CKA_CLASS: c_ulong = 0x0;
+const CKA_LABEL: c_ulong = 0x3;
+const CKA_KEY_TYPE: c_ulong = 0x100;
+const CKA_EC_PARAMS: c_ulong = 0x180;
+const CKA_EC_POINT: c_ulong = 0x181;
+const CKM_ECDSA: c_ulong = 0x1041;
+
+/// The DER encoded OIDs of the curves, as found in `CKA_EC_PARAMS`.
+const P256_PARAMS: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const P384_PARAMS: &[u8] = &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x22];
+const P521_PARAMS: &[u8] = &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x23];
+
+#[repr(C)]
+struct CkAttribute {
+    kind: c_ulong,
+    value: *mut c_void,
+    value_len: c_ulong,
+}
+
+#[repr(C)]
+struct CkMechanism {
+    mechanism: c_ulong,
+    parameter: *mut c_void,
+    parameter_len: c_ulong,
+}
+
+#[repr(C)]
+struct CkTokenInfo {
+    label: [u8; 32],
+    manufacturer_id: [u8; 32],
+    model: [u8; 16],
+    serial_number: [u8; 16],
+    flags: c_ulong,
+    counts: [c_ulong; 10],
+    hardware_version: [u8; 2],
+    firmware_version: [u8; 2],
+    utc_time: [u8; 16],
+}
+
+type Unused = Option<unsafe extern "C" fn()>;
+
+/// The beginning of `CK_FUNCTION_LIST`, up to the functions used here.
+#[repr(C)]
+struct CkFunctionList {
+    version: [u8; 2],
+    initialize: unsafe extern "C" fn(*mut c_void) -> CkRv,
+    finalize: unsafe extern "C" fn(*mut c_void) -> CkRv,
+    get_info: Unused,
+    get_function_list: Unused,
+    get_slot_list: unsafe extern "C" fn(u8, *mut CkSlotId, *mut c_ulong) -> CkRv,
+    get_slot_info: Unused,
+    get_token_info: unsafe extern "C" fn(CkSlotId, *mut CkTokenInfo) -> CkRv,
+    get_mechanism_list: Unused,
+    get_mechanism_info: Unused,
+    init_token: Unused,
+    init_pin: Unused,
+    set_pin: Unused,
+    open_session: unsafe extern "C" fn(
+        CkSlotId,
+        c_ulong,
+        *mut c_void,
+        *mut c_void,
+        *mut CkSessionHandle,
+    ) -> CkRv,
+    close_session: unsafe extern "C" fn(CkSessionHandle) -> CkRv,
+    close_all_sessions: Unused,
+    get_session_info: Unused,
+    get_operation_state: Unused,
+    set_operation_state: Unused,
+    login: unsafe extern "C" fn(CkSessionHandle, c_ulong, *const u8, c_ulong) -> CkRv,
+    logout: unsafe extern "C" fn(CkSessionHandle) -> CkRv,
+    create_object: Unused,
+    copy_object: Unused,
+    destroy_object: Unused,
+    get_object_size: Unused,
+    get_attribute_value:
+        unsafe extern "C" fn(CkSessionHandle, CkObjectHandle, *mut CkAttribute, c_ulong) -> CkRv,
+    set_attribute_value: Unused,
+    find_objects_init: unsafe extern "C" fn(CkSessionHandle, *mut CkAttribute, c_ulong) -> CkRv,
+    find_objects:
+        unsafe extern "C" fn(CkSessionHandle, *mut CkObjectHandle, c_ulong, *mut c_ulong) -> CkRv,
+    find_objects_final: unsafe extern "C" fn(CkSessionHandle) -> CkRv,
+    encrypt_init: Unused,
+    encrypt: Unused,
+    encrypt_update: Unused,
+    encrypt_final: Unused,
+    decrypt_init: Unused,
+    decrypt: Unused,
+    decrypt_update: Unused,
+    decrypt_final: Unused,
+    digest_init: Unused,
+    digest: Unused,
+    digest_update: Unused,
+    digest_key: Unused,
+    digest_final: Unused,
+    sign_init: unsafe extern "C" fn(CkSessionHandle, *mut CkMechanism, CkObjectHandle) -> CkRv,
+    sign: unsafe extern "C" fn(CkSessionHandle, *const u8, c_ulong, *mut u8, *mut c_ulong) -> CkRv,
+}
+
+/// Fail with the name of the PKCS#11 function if it did not return `CKR_OK`.
+fn check(rv: CkRv, function: &str) -> Result<(), anyhow::Error> {
+    if rv == CKR_OK {
+        Ok(())
+    } else {
+        let name = match rv {
+            0x68 => " (CKR_KEY_FUNCTION_NOT_PERMITTED)",
+            0x70 => " (CKR_MECHANISM_INVALID)",
+            0xa0 => " (CKR_PIN_INCORRECT)",
+            0xa3 => " (CKR_PIN_EXPIRED)",
+            0xa4 => " (CKR_PIN_LOCKED)",
+            0xe0 => " (CKR_TOKEN_NOT_PRESENT)",
+            _ => "",
+        };
+        Err(anyhow::anyhow!(
+            "{} failed with CKR 0x{:x}{}.",
+            function,
+            rv,
+            name
+        ))
+    }
+}
+
+/// The PKCS#11 modules used by the keys of the process, by the address of their function list.
+///
+/// A module is initialised once for the whole process, and a token logged in once for all the
+/// sessions on it, so they are only finalised and logged out of when the last key using them is
+/// dropped, rather than by the first one from under the others.
+static MODULES: Mutex<BTreeMap<usize, ModuleUse>> = Mutex::new(BTreeMap::new());
+
+struct ModuleUse {
+    /// Whether the module was initialised by the keys, rather than by another user of the module
+    /// in the process, which must not be finalised.
+    initialized: bool,
+    keys: usize,
+    tokens: BTreeMap<CkSlotId, TokenUse>,
+}
+
+struct TokenUse {
+    /// Whether the token was logged in by the keys, rather than by another user of the module.
+    logged_in: bool,
+    keys: usize,
+}
+
+fn modules() -> MutexGuard<'static, BTreeMap<usize, ModuleUse>> {
+    // The uses stay consistent even if a thread panicked while holding the lock
+    MODULES.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+/// An Elliptic Curve private key on a PKCS#11 token, with a logged in session to sign with it.
+pub struct Pkcs11Key {
+    library: *mut c_void,
+    functions: *const CkFunctionList,
+    session: CkSessionHandle,
+    key: CkObjectHandle,
+    key_label: String,
+    alg: Algorithm,
+    /// Whether the key counts as a user of its module in `MODULES`, and of the token in `slot`.
+    uses_module: bool,
+    slot: Option<CkSlotId>,
+}
+
+impl Pkcs11Key {
+    /// Load the PKCS#11 `module`, log in the token labelled `token_label` with `pin` and find the
+    /// Elliptic Curve private key labelled `key_label`.
+    pub fn open(
+        module: &Path,
+        token_label: &str,
+        key_label: &str,
+        pin: &str,
+    ) -> Result<Self, anyhow::Error> {
+        let path = CString::new(module.as_os_str().as_bytes())
+            .context("The PKCS#11 module path must not contain NUL bytes.")?;
+        // Safety: `path` is a valid C string
+        let library = unsafe { libc::dlopen(path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if library.is_null() {
+            // Safety: `dlerror` returns a valid C string after `dlopen` failed
+            let error = unsafe { CStr::from_ptr(libc::dlerror()) };
+            return Err(anyhow::anyhow!(
+                "Failed to load the PKCS#11 module {}: {}",
+                module.display(),
+                error.to_string_lossy()
+            ));
+        }
+        let mut pkcs11_key = Pkcs11Key {
+            library,
+            functions: ptr::null(),
+            session: 0,
+            key: 0,
+            key_label: String::new(),
+            alg: Algorithm::ES512,
+            uses_module: false,
+            slot: None,
+        };
+
+        // Safety: `library` is a loaded library and `C_GetFunctionList` has this signature
+        let get_function_list =
+            unsafe { libc::dlsym(library, b"C_GetFunctionList\0".as_ptr() as _) };
+        if get_function_list.is_null() {
+            return Err(anyhow::anyhow!(
+                "{} is not a PKCS#11 module, it has no `C_GetFunctionList`.",
+                module.display()
+            ));
+        }
+        let get_function_list: unsafe extern "C" fn(*mut *const CkFunctionList) -> CkRv =
+            unsafe { std::mem::transmute(get_function_list) };
+        let mut functions = ptr::null();
+        check(
+            unsafe { get_function_list(&mut functions) },
+            "C_GetFunctionList",
+        )?;
+        pkcs11_key.functions = functions;
+
+        // Held until the key is open, so that other keys see the module and token as they are
+        let mut modules = modules();
+        let module = match modules.entry(functions as usize) {
+            Entry::Occupied(module) => module.into_mut(),
+            Entry::Vacant(entry) => {
+                let rv = unsafe { ((*functions).initialize)(ptr::null_mut()) };
+                if rv != CKR_CRYPTOKI_ALREADY_INITIALIZED {
+                    check(rv, "C_Initialize")?;
+                }
+                entry.insert(ModuleUse {
+                    initialized: rv == CKR_OK,
+                    keys: 0,
+                    tokens: BTreeMap::new(),
+                })
+            }
+        };
+        module.keys += 1;
+        pkcs11_key.uses_module = true;
+
+        let slot = pkcs11_key.find_token(token_label)?;
+        pkcs11_key.open_session(slot, pin, module)?;
+        drop(modules);
+        pkcs11_key.find_key(key_label)?;
+        Ok(pkcs11_key)
+    }
+
+    fn functions(&self) -> &CkFunctionList {
+        // Safety: `functions` is set as soon as the module is initialised, and stays valid until
+        // it is unloaded on drop
+        unsafe { &*self.functions }
+    }
+
+    /// The slot of the token labelled `token_label`.
+    fn find_token(&self, token_label: &str) -> Result<CkSlotId, anyhow::Error> {
+        let functions = self.functions();
+        let mut count = 0;
+        check(
+            unsafe { (functions.get_slot_list)(1, ptr::null_mut(), &mut count) },
+            "C_GetSlotList",
+        )?;
+        let mut slots = vec![0; count as usize];
+        check(
+            unsafe { (functions.get_slot_list)(1, slots.as_mut_ptr(), &mut count) },
+            "C_GetSlotList",
+        )?;
+        slots.truncate(count as usize);
+
+        let mut labels = Vec::new();
+        for slot in slots {
+            let mut info = std::mem::MaybeUninit::<CkTokenInfo>::uninit();
+            check(
+                unsafe { (functions.get_token_info)(slot, info.as_mut_ptr()) },
+                "C_GetTokenInfo",
+            )?;
+            // Safety: `C_GetTokenInfo` succeeded
+            let info = unsafe { info.assume_init() };
+            // Labels are padded with spaces
+            let label = String::from_utf8_lossy(&info.label).trim_end().to_owned();
+            if label == token_label {
+                return Ok(slot);
+            }
+            labels.push(label);
+        }
+        Err(anyhow::anyhow!(
+            "No PKCS#11 token labelled `{}`, found: {}.",
+            token_label,
+            if labels.is_empty() {
+                "none".to_owned()
+            } else {
+                labels.join(", ")
+            }
+        ))
+    }
+
+    fn open_session(
+        &mut self,
+        slot: CkSlotId,
+        pin: &str,
+        module: &mut ModuleUse,
+    ) -> Result<(), anyhow::Error> {
+        let (open_session, login) = (self.functions().open_session, self.functions().login);
+        let mut session = 0;
+        check(
+            unsafe {
+                open_session(
+                    slot,
+                    CKF_SERIAL_SESSION,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    &mut session,
+                )
+            },
+            "C_OpenSession",
+        )?;
+        self.session = session;
+
+        // The login is shared by all the sessions on the token
+        let token = match module.tokens.entry(slot) {
+            Entry::Occupied(token) => token.into_mut(),
+            Entry::Vacant(entry) => {
+                let rv = unsafe { login(session, CKU_USER, pin.as_ptr(), pin.len() as c_ulong) };
+                if rv != CKR_USER_ALREADY_LOGGED_IN {
+                    check(rv, "C_Login").context("Failed to log in the PKCS#11 token.")?;
+                }
+                entry.insert(TokenUse {
+                    logged_in: rv == CKR_OK,
+                    keys: 0,
+                })
+            }
+        };
+        token.keys += 1;
+        self.slot = Some(slot);
+        Ok(())
+    }
+
+    /// Find the EC private key labelled `key_label`, and the algorithm matching its curve.
+    fn find_key(&mut self, key_label: &str) -> Result<(), anyhow::Error> {
+        let key = match self.find_objects(CKO_PRIVATE_KEY, key_label)?.as_slice() {
+            [key] => *key,
+            [] => {
+                return Err(anyhow::anyhow!(
+                    "No EC private key labelled `{}` on the PKCS#11 token.",
+                    key_label
+                ))
+            }
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Several EC private keys are labelled `{}` on the PKCS#11 token.",
+                    key_label
+                ))
+            }
+        };
+        self.alg = match self.attribute_value(key, CKA_EC_PARAMS)?.as_slice() {
+            P256_PARAMS => Algorithm::ES256,
+            P384_PARAMS => Algorithm::ES384,
+            P521_PARAMS => Algorithm::ES512,
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "The curve of the key `{}` is not supported, expected P-256, P-384 or P-521.",
+                    key_label
+                ))
+            }
+        };
+        self.key = key;
+        self.key_label = key_label.to_owned();
+        Ok(())
+    }
+
+    /// Find the EC keys of class `class` labelled `label`, stopping after two of them.
+    fn find_objects(
+        &self,
+        class: c_ulong,
+        label: &str,
+    ) -> Result<Vec<CkObjectHandle>, anyhow::Error> {
+        let functions = self.functions();
+        let mut class = class;
+        let mut key_type = CKK_EC;
+        let mut template = [
+            attribute(CKA_CLASS, &mut class),
+            attribute(CKA_KEY_TYPE, &mut key_type),
+            CkAttribute {
+                kind: CKA_LABEL,
+                value: label.as_ptr() as *mut c_void,
+                value_len: label.len() as c_ulong,
+            },
+        ];
+        check(
+            unsafe {
+                (functions.find_objects_init)(
+                    self.session,
+                    template.as_mut_ptr(),
+                    template.len() as c_ulong,
+                )
+            },
+            "C_FindObjectsInit",
+        )?;
+        let mut objects = vec![0; 2];
+        let mut count = 0;
+        let rv = unsafe {
+            (functions.find_objects)(
+                self.session,
+                objects.as_mut_ptr(),
+                objects.len() as c_ulong,
+                &mut count,
+            )
+        };
+        unsafe { (functions.find_objects_final)(self.session) };
+        check(rv, "C_FindObjects")?;
+        objects.truncate(count as usize);
+        Ok(objects)
+    }
+
+    /// The value of the attribute `kind` of `object`.
+    fn attribute_value(
+        &self,
+        object: CkObjectHandle,
+        kind: c_ulong,
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        let functions = self.functions();
+        let mut attribute = CkAttribute {
+            kind,
+            value: ptr::null_mut(),
+            value_len: 0,
+        };
+        check(
+            unsafe { (functions.get_attribute_value)(self.session, object, &mut attribute, 1) },
+            "C_GetAttributeValue",
+        )?;
+        let mut value = vec![0u8; attribute.value_len as usize];
+        attribute.value = value.as_mut_ptr() as *mut c_void;
+        check(
+            unsafe { (functions.get_attribute_value)(self.session, object, &mut attribute, 1) },
+            "C_GetAttributeValue",
+        )?;
+        value.truncate(attribute.value_len as usize);
+        Ok(value)
+    }
+}
+
+impl SigningKey for Pkcs11Key {
+    fn alg(&self) -> Result<Algorithm, anyhow::Error> {
+        Ok(self.alg)
+    }
+
+    /// The public key of the key pair, read from the public key with the same label on the token.
+    fn public_key(&self) -> Result<EcKey<Public>, anyhow::Error> {
+        let public_key = *self
+            .find_objects(CKO_PUBLIC_KEY, &self.key_label)?
+            .first()
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "No EC public key labelled `{}` on the PKCS#11 token.",
+                    self.key_label
+                )
+            })?;
+        let ec_point = self.attribute_value(public_key, CKA_EC_POINT)?;
+        // The uncompressed point should be DER encoded as an OCTET STRING, but some modules omit
+        // the DER header: keep what follows it, if any
+        let point_len = 1 + 2 * self.alg.coordinate_len();
+        let point = &ec_point[ec_point.len().saturating_sub(point_len)..];
+        let group = EcGroup::from_curve_name(self.alg.curve())?;
+        let mut ctx = BigNumContext::new()?;
+        let point = EcPoint::from_bytes(&group, point, &mut ctx)
+            .context("Invalid EC point for the public key on the PKCS#11 token.")?;
+        Ok(EcKey::from_public_key(&group, &point)?)
+    }
+
+    /// Sign a payload on the token, `CKM_ECDSA` already producing the JWS format.
+    fn sign(&self, payload: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        let functions = self.functions();
+        // `CKM_ECDSA` signs a digest computed beforehand
+        let hash = openssl::hash::hash(self.alg.digest(), payload)?;
+        let mut mechanism = CkMechanism {
+            mechanism: CKM_ECDSA,
+            parameter: ptr::null_mut(),
+            parameter_len: 0,
+        };
+        check(
+            unsafe { (functions.sign_init)(self.session, &mut mechanism, self.key) },
+            "C_SignInit",
+        )?;
+        let mut signature = vec![0; 2 * self.alg.coordinate_len()];
+        let mut signature_len = signature.len() as c_ulong;
+        check(
+            unsafe {
+                (functions.sign)(
+                    self.session,
+                    hash.as_ptr(),
+                    hash.len() as c_ulong,
+                    signature.as_mut_ptr(),
+                    &mut signature_len,
+                )
+            },
+            "C_Sign",
+        )?;
+        if signature_len as usize != signature.len() {
+            return Err(anyhow::anyhow!(
+                "The PKCS#11 token returned a {} bytes signature, expected {} bytes for {}.",
+                signature_len,
+                signature.len(),
+                self.alg
+            ));
+        }
+        Ok(signature)
+    }
+}
+
+impl Drop for Pkcs11Key {
+    fn drop(&mut self) {
+        if self.uses_module {
+            let functions = self.functions();
+            let mut modules = modules();
+            let module = modules
+                .get_mut(&(self.functions as usize))
+                .expect("The module of a key is in use.");
+            if let Some(slot) = self.slot {
+                let token = module
+                    .tokens
+                    .get_mut(&slot)
+                    .expect("The token of a key is in use.");
+                token.keys -= 1;
+                if token.keys == 0 {
+                    // Logging out needs a session, so happens before closing the last one
+                    if token.logged_in {
+                        unsafe { (functions.logout)(self.session) };
+                    }
+                    module.tokens.remove(&slot);
+                }
+            }
+            if self.session != 0 {
+                unsafe { (functions.close_session)(self.session) };
+            }
+            module.keys -= 1;
+            if module.keys == 0 {
+                if module.initialized {
+                    unsafe { (functions.finalize)(ptr::null_mut()) };
+                }
+                modules.remove(&(self.functions as usize));
+            }
+        }
+        unsafe { libc::dlclose(self.library) };
+    }
+}
+
+fn attribute(kind: c_ulong, value: &mut c_ulong) -> CkAttribute {
+    CkAttribute {
+        kind,
+        value: value as *mut c_ulong as *mut c_void,
+        value_len: std::mem::size_of::<c_ulong>() as c_ulong,
+    }
+}
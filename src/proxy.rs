@@ -0,0 +1,365 @@
+//! A local HTTP proxy signing the requests it forwards upstream.
+use crate::http::{self, Request, Response, Url};
+use std::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::Duration,
+};
+use tlsign::{Header, Signer};
+
+/// How long the proxy waits for a client to send its request or read the response, so that an
+/// abandoned connection does not keep its thread forever.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Signs the requests it receives and forwards them to `upstream`.
+pub struct Proxy {
+    pub upstream: Url,
+    pub signer: Signer,
+    /// Sign the body alone, as the `X-TL-Signature` header, rather than using request signing v2.
+    pub legacy: bool,
+    /// The names of the request headers to include in the signature, besides `Idempotency-Key`.
+    pub signed_headers: Vec<String>,
+}
+
+impl Proxy {
+    /// Answer the requests accepted by `listener`, until the process is stopped.
+    ///
+    /// Each connection is read on its own thread, so that an idle one, e.g. opened ahead of time by
+    /// a browser, does not block the others. The requests are then signed and forwarded one at a
+    /// time.
+    pub fn serve(&self, listener: TcpListener) -> anyhow::Result<()> {
+        let (sender, requests) = mpsc::channel();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let sender = sender.clone();
+                std::thread::spawn(move || {
+                    if let Some(request) = read_client(stream) {
+                        // The proxy only stops with the process
+                        let _ = sender.send(request);
+                    }
+                });
+            }
+        });
+        for (stream, request) in requests {
+            // A failing request must not stop the proxy
+            if let Err(error) = self.serve_client(stream, request) {
+                eprintln!("Error: {:#}", error);
+            }
+        }
+        Ok(())
+    }
+
+    fn serve_client(&self, mut stream: TcpStream, request: Request) -> anyhow::Result<()> {
+        if let Err(error) = self.check_signed_headers(&request) {
+            http::write_response(&mut stream, &error_response(400, "Bad Request", &error))?;
+            return Err(error);
+        }
+        let response = match self.forward(&request) {
+            Ok(response) => response,
+            Err(error) => error_response(502, "Bad Gateway", &error),
+        };
+        eprintln!(
+            "{} {} -> {} {}",
+            request.method, request.target, response.status, response.reason
+        );
+        http::write_response(&mut stream, &response)
+    }
+
+    fn check_signed_headers(&self, request: &Request) -> anyhow::Result<()> {
+        match self
+            .signed_headers
+            .iter()
+            .find(|name| http::header_value(&request.headers, name).is_none())
+        {
+            Some(name) => Err(anyhow::anyhow!(
+                "The request is missing the `{}` header to sign.",
+                name
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Sign the request and send it upstream.
+    fn forward(&self, request: &Request) -> anyhow::Result<Response> {
+        let url = self.upstream.join(&request.target);
+        let mut headers: Vec<Header> = request
+            .headers
+            .iter()
+            .filter(|header| {
+                !http::is_hop_by_hop(header.name())
+                    && !["Tl-Signature", "X-TL-Signature"]
+                        .iter()
+                        .any(|name| name.eq_ignore_ascii_case(header.name()))
+            })
+            .cloned()
+            .collect();
+        if http::header_value(&headers, "Idempotency-Key").is_none() {
+            headers.push(Header::new(
+                "Idempotency-Key",
+                crate::random_uuid()?.to_string(),
+            ));
+        }
+
+        let signature = if self.legacy {
+            Header::new(
+                "X-TL-Signature",
+                self.signer.sign(&request.body)?.to_string(),
+            )
+        } else {
+            let signed_headers: Vec<_> = std::iter::once("Idempotency-Key")
+                .chain(self.signed_headers.iter().map(String::as_str))
+                .filter_map(|name| {
+                    http::header_value(&headers, name).map(|value| Header::new(name, value))
+                })
+                .collect();
+            let jws = self.signer.sign_request(
+                &request.method,
+                url.path(),
+                &signed_headers,
+                &request.body,
+            )?;
+            Header::new("Tl-Signature", jws.to_string())
+        };
+        headers.push(signature);
+
+        http::send(&url, &request.method, &headers, &request.body)
+    }
+}
+
+/// Read the request of a client, answering 400 if it is invalid, or 413 if its body is too large.
+fn read_client(mut stream: TcpStream) -> Option<(TcpStream, Request)> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT)).ok()?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT)).ok()?;
+    // A connection opened ahead of time may be closed or time out without a request
+    match stream.peek(&mut [0]) {
+        Ok(0) | Err(_) => return None,
+        Ok(_) => {}
+    }
+    match http::read_request(&mut stream) {
+        Ok(request) => Some((stream, request)),
+        Err(error) => {
+            let response = if error.is::<http::BodyTooLarge>() {
+                error_response(413, "Payload Too Large", &error)
+            } else {
+                error_response(400, "Bad Request", &error)
+            };
+            let _ = http::write_response(&mut stream, &response);
+            eprintln!("Error: {:#}", error);
+            None
+        }
+    }
+}
+
+/// A plain text response explaining why the proxy could not forward the request.
+fn error_response(status: u16, reason: &str, error: &anyhow::Error) -> Response {
+    Response {
+        status,
+        reason: reason.to_owned(),
+        headers: vec![Header::new("Content-Type", "text/plain; charset=utf-8")],
+        body: format!("tlsign proxy: {:#}\n", error).into_bytes(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::{ec::EcKey, pkey::Public};
+    use std::{
+        io::{Read, Write},
+        net::SocketAddr,
+        sync::mpsc::Receiver,
+        thread,
+    };
+    use tlsign::{generate_key, Algorithm, DetachedJws, SigningKey};
+
+    /// A stand-in for the API, passing on the requests it receives and answering `201`.
+    fn upstream() -> (Url, Receiver<Request>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let request = http::read_request(&mut stream).unwrap();
+                sender.send(request).unwrap();
+                let response = Response {
+                    status: 201,
+                    reason: "Created".to_owned(),
+                    headers: vec![Header::new("Content-Type", "application/json")],
+                    body: br#"{"id":"abc"}"#.to_vec(),
+                };
+                http::write_response(&mut stream, &response).unwrap();
+            }
+        });
+        (url.parse().unwrap(), requests)
+    }
+
+    /// Start a proxy to `upstream`, returning its address and the public key it signs for.
+    fn proxy(upstream: Url, legacy: bool, signed_headers: &[&str]) -> (SocketAddr, EcKey<Public>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let key = generate_key(Algorithm::ES512).unwrap();
+        let public_key = SigningKey::public_key(&key).unwrap();
+        let signed_headers = signed_headers.iter().map(|name| name.to_string()).collect();
+        thread::spawn(move || {
+            let proxy = Proxy {
+                upstream,
+                signer: Signer::new(key, "45fc75cf-5649-4134-84b3-192c2c78e990").unwrap(),
+                legacy,
+                signed_headers,
+            };
+            proxy.serve(listener)
+        });
+        (address, public_key)
+    }
+
+    fn url(address: SocketAddr, target: &str) -> Url {
+        format!("http://{}{}", address, target).parse().unwrap()
+    }
+
+    fn names(headers: &[Header]) -> Vec<&str> {
+        headers.iter().map(Header::name).collect()
+    }
+
+    #[test]
+    fn forward_signed_requests() {
+        let (upstream, requests) = upstream();
+        let (proxy, public_key) = proxy(upstream, false, &["X-Custom"]);
+        let body = br#"{"amount_in_minor":100}"#;
+        let response = http::send(
+            &url(proxy, "/v3/payments?a=b"),
+            "POST",
+            &[
+                Header::new("Idempotency-Key", "1"),
+                Header::new("X-Custom", "a"),
+                Header::new("X-Unsigned", "b"),
+                Header::new("Keep-Alive", "timeout=5"),
+                Header::new("Proxy-Authorization", "Basic YTpi"),
+                Header::new("TE", "trailers"),
+                Header::new("Tl-Signature", "stale"),
+            ],
+            body,
+        )
+        .unwrap();
+        assert_eq!(response.status, 201);
+        assert_eq!(response.body, br#"{"id":"abc"}"#);
+
+        let request = requests.recv().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.target, "/v3/payments?a=b");
+        assert_eq!(request.body, body);
+        // The hop-by-hop headers of the client are replaced by those of the proxy
+        assert_eq!(
+            names(&request.headers),
+            [
+                "Host",
+                "Idempotency-Key",
+                "X-Custom",
+                "X-Unsigned",
+                "Tl-Signature",
+                "Content-Length",
+                "Connection",
+            ]
+        );
+
+        let jws: DetachedJws = http::header_value(&request.headers, "Tl-Signature")
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(
+            jws.header().unwrap()["tl_headers"],
+            "Idempotency-Key,X-Custom"
+        );
+        // The path is signed without the query string
+        jws.verify_request(
+            "POST",
+            "/v3/payments",
+            &request.headers,
+            &request.body,
+            &public_key,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn add_an_idempotency_key() {
+        let (upstream, requests) = upstream();
+        let (proxy, public_key) = proxy(upstream, false, &[]);
+        http::send(&url(proxy, "/v3/payments"), "POST", &[], b"{}").unwrap();
+
+        let request = requests.recv().unwrap();
+        let idempotency_key = http::header_value(&request.headers, "Idempotency-Key").unwrap();
+        assert!(idempotency_key.parse::<uuid::Uuid>().is_ok());
+        let jws: DetachedJws = http::header_value(&request.headers, "Tl-Signature")
+            .unwrap()
+            .parse()
+            .unwrap();
+        jws.verify_request("POST", "/v3/payments", &request.headers, b"{}", &public_key)
+            .unwrap();
+    }
+
+    #[test]
+    fn sign_the_body_alone_with_legacy() {
+        let (upstream, requests) = upstream();
+        let (proxy, public_key) = proxy(upstream, true, &[]);
+        http::send(&url(proxy, "/payouts"), "POST", &[], b"{}").unwrap();
+
+        let request = requests.recv().unwrap();
+        assert!(http::header_value(&request.headers, "Tl-Signature").is_none());
+        let jws: DetachedJws = http::header_value(&request.headers, "X-TL-Signature")
+            .unwrap()
+            .parse()
+            .unwrap();
+        jws.verify(b"{}", &public_key).unwrap();
+    }
+
+    #[test]
+    fn reject_requests_missing_a_header_to_sign() {
+        let (upstream, requests) = upstream();
+        let (proxy, _) = proxy(upstream, false, &["X-Custom"]);
+        let response = http::send(&url(proxy, "/v3/payments"), "POST", &[], b"{}").unwrap();
+        assert_eq!(response.status, 400);
+        assert_eq!(
+            String::from_utf8(response.body).unwrap(),
+            "tlsign proxy: The request is missing the `X-Custom` header to sign.\n"
+        );
+        assert!(requests
+            .recv_timeout(std::time::Duration::from_millis(100))
+            .is_err());
+    }
+
+    #[test]
+    fn reject_oversized_bodies() {
+        let (upstream, _requests) = upstream();
+        let (proxy, _) = proxy(upstream, false, &[]);
+        let mut stream = TcpStream::connect(proxy).unwrap();
+        stream
+            .write_all(b"POST /v3/payments HTTP/1.1\r\nContent-Length: 99999999999999999\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"),
+            "{}",
+            response
+        );
+
+        // The proxy is still serving
+        let response = http::send(&url(proxy, "/v3/payments"), "POST", &[], b"{}").unwrap();
+        assert_eq!(response.status, 201);
+    }
+
+    #[test]
+    fn idle_connections_do_not_block_others() {
+        let (upstream, _requests) = upstream();
+        let (proxy, _) = proxy(upstream, false, &[]);
+        let _idle = TcpStream::connect(
+            proxy
+                .to_string()
+                .trim_start_matches("http://")
+                .trim_end_matches('/'),
+        )
+        .unwrap();
+        let response = http::send(&url(proxy, "/v3/payments"), "POST", &[], b"{}").unwrap();
+        assert_eq!(response.status, 201);
+    }
+}
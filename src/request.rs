@@ -0,0 +1,71 @@
+use std::{fmt, str::FromStr};
+
+/// An HTTP header included in a request signing v2 signature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    name: String,
+    value: String,
+}
+
+impl Header {
+    pub fn new(name: impl Into<String>, value: impl Into<String>) -> Self {
+        Header {
+            name: name.into(),
+            value: value.into(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+}
+
+/// Parse a header of the form `name:value`, ignoring the whitespace around the value.
+impl FromStr for Header {
+    type Err = HeaderParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s.split_once(':').ok_or(HeaderParseError)?;
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(HeaderParseError);
+        }
+        Ok(Header {
+            name: name.to_owned(),
+            value: value.trim().to_owned(),
+        })
+    }
+}
+
+/// The error returned when parsing a [`Header`] that is not of the form `name:value`.
+#[derive(Debug)]
+pub struct HeaderParseError;
+
+impl fmt::Display for HeaderParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a header must be of the form `name:value`")
+    }
+}
+
+impl std::error::Error for HeaderParseError {}
+
+/// Build the payload signed by TrueLayer's request signing v2: the request line, followed by
+/// the signed headers and the body.
+///
+/// ```text
+/// POST /v3/payments
+/// Idempotency-Key: 1234
+/// {"amount_in_minor":100}
+/// ```
+pub fn v2_signing_payload(method: &str, path: &str, headers: &[Header], body: &[u8]) -> Vec<u8> {
+    let mut payload = format!("{} {}\n", method.to_uppercase(), path).into_bytes();
+    for header in headers {
+        payload.extend(format!("{}: {}\n", header.name, header.value).into_bytes());
+    }
+    payload.extend(body);
+    payload
+}
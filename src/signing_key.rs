@@ -0,0 +1,60 @@
+use crate::Algorithm;
+use openssl::{
+    ec::{EcKey, EcKeyRef},
+    ecdsa::EcdsaSig,
+    pkey::{Private, Public},
+};
+
+/// A private key JWS can be signed with, wherever it is kept: in memory, in an agent or on an HSM.
+pub trait SigningKey {
+    /// The JWS algorithm matching the curve of the key.
+    fn alg(&self) -> Result<Algorithm, anyhow::Error>;
+
+    /// The public key of the key pair.
+    fn public_key(&self) -> Result<EcKey<Public>, anyhow::Error>;
+
+    /// Sign a payload, returning the raw JWS signature, i.e. `r` and `s` padded to the length of
+    /// the curve and concatenated.
+    ///
+    /// Check section A.4 of RFC7515 for the details: https://www.rfc-editor.org/rfc/rfc7515.txt
+    fn sign(&self, payload: &[u8]) -> Result<Vec<u8>, anyhow::Error>;
+}
+
+/// A private key in memory, e.g. read from a file.
+impl SigningKey for EcKeyRef<Private> {
+    fn alg(&self) -> Result<Algorithm, anyhow::Error> {
+        Algorithm::from_curve(self.group().curve_name())
+    }
+
+    fn public_key(&self) -> Result<EcKey<Public>, anyhow::Error> {
+        Ok(EcKey::from_public_key(self.group(), self.public_key())?)
+    }
+
+    fn sign(&self, payload: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        let alg = SigningKey::alg(self)?;
+        let hash = openssl::hash::hash(alg.digest(), payload)?;
+        let structured_signature = EcdsaSig::sign(&hash, self)?;
+
+        let r = structured_signature
+            .r()
+            .to_vec_padded(alg.coordinate_len() as i32)?;
+        let s = structured_signature
+            .s()
+            .to_vec_padded(alg.coordinate_len() as i32)?;
+        Ok([r, s].concat())
+    }
+}
+
+impl SigningKey for EcKey<Private> {
+    fn alg(&self) -> Result<Algorithm, anyhow::Error> {
+        SigningKey::alg(&**self)
+    }
+
+    fn public_key(&self) -> Result<EcKey<Public>, anyhow::Error> {
+        SigningKey::public_key(&**self)
+    }
+
+    fn sign(&self, payload: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        SigningKey::sign(&**self, payload)
+    }
+}
@@ -0,0 +1,90 @@
+use crate::{public_key_from_jwk, DetachedJws, Header};
+use anyhow::Context;
+use serde_json::Value;
+
+/// The `jku` of the JWKS TrueLayer signs webhooks with, in production and in sandbox.
+pub const TRUELAYER_WEBHOOK_JKUS: [&str; 2] = [
+    "https://webhooks.truelayer.com/.well-known/jwks",
+    "https://webhooks.truelayer-sandbox.com/.well-known/jwks",
+];
+
+/// Verifies webhooks signed using request signing v2, as sent by TrueLayer with the
+/// `Tl-Signature` header, against the keys of a JWKS.
+pub struct WebhookVerifier {
+    keys: Vec<Value>,
+    allowed_jkus: Vec<String>,
+}
+
+impl WebhookVerifier {
+    /// Verify against the keys of `jwks`, i.e. `{"keys": [...]}`, accepting signatures whose `jku`
+    /// is one of TrueLayer's.
+    pub fn new(jwks: &Value) -> Result<Self, anyhow::Error> {
+        let keys = jwks
+            .get("keys")
+            .and_then(Value::as_array)
+            .context("The JWKS must be an object with a `keys` array.")?;
+        Ok(WebhookVerifier {
+            keys: keys.clone(),
+            allowed_jkus: TRUELAYER_WEBHOOK_JKUS
+                .iter()
+                .map(|jku| (*jku).to_owned())
+                .collect(),
+        })
+    }
+
+    /// Accept signatures whose `jku` is one of `jkus`, instead of TrueLayer's.
+    pub fn with_allowed_jkus(mut self, jkus: Vec<String>) -> Self {
+        self.allowed_jkus = jkus;
+        self
+    }
+
+    /// Verify the signature of a webhook. The key is selected by the `kid` of the signature,
+    /// provided its `jku` is allowed and its `alg` matches the key.
+    pub fn verify(
+        &self,
+        signature: &DetachedJws,
+        method: &str,
+        path: &str,
+        headers: &[Header],
+        body: &[u8],
+    ) -> Result<(), anyhow::Error> {
+        let jws_header = signature.header()?;
+        let jku = jws_header
+            .get("jku")
+            .and_then(Value::as_str)
+            .context("The JWS header is missing `jku`.")?;
+        if !self.allowed_jkus.iter().any(|allowed| allowed == jku) {
+            return Err(anyhow::anyhow!(
+                "The `jku` {} is not allowed, expected one of {}.",
+                jku,
+                self.allowed_jkus.join(", ")
+            ));
+        }
+
+        let kid = jws_header
+            .get("kid")
+            .and_then(Value::as_str)
+            .context("The JWS header is missing `kid`.")?;
+        let jwk = self
+            .keys
+            .iter()
+            .find(|jwk| jwk.get("kid").and_then(Value::as_str) == Some(kid))
+            .ok_or_else(|| anyhow::anyhow!("The JWKS has no key with `kid` {}.", kid))?;
+
+        let alg = signature.alg()?;
+        if let Some(jwk_alg) = jwk.get("alg").and_then(Value::as_str) {
+            if jwk_alg != alg.name() {
+                return Err(anyhow::anyhow!(
+                    "The JWS algorithm {} does not match the algorithm {} of the key {}.",
+                    alg,
+                    jwk_alg,
+                    kid
+                ));
+            }
+        }
+        let public_key = public_key_from_jwk(jwk)
+            .with_context(|| format!("Failed to parse the key {} of the JWKS.", kid))?;
+
+        signature.verify_request(method, path, headers, body, &public_key)
+    }
+}
@@ -0,0 +1,655 @@
+//! Known-answer and round-trip tests of the JWS construction, in particular the fixed-length
+//! encoding of ECDSA signatures, and of deterministic ECDSA (RFC6979).
+use openssl::{
+    bn::{BigNum, BigNumContext},
+    ec::{Asn1Flag, EcGroup, EcKey, EcPoint},
+    pkey::{PKey, Private},
+    x509::X509,
+};
+use serde_json::json;
+use tlsign::{
+    base64_decode, base64_encode, generate_key, get_jws, public_jwk, public_key_fingerprint,
+    public_key_from_jwk, self_signed_certificate, v2_signing_payload, verify_ecdsa, Algorithm,
+    DetachedJws, DeterministicKey, Header, Signer, SigningKey, WebhookVerifier,
+    TRUELAYER_WEBHOOK_JKUS,
+};
+
+const KID: &str = "45fc75cf-5649-4134-84b3-192c2c78e990";
+
+/// The ES512 example of appendix A.4 of RFC7515.
+const RFC7515_A4_JWK_X: &str =
+    "AekpBQ8ST8a8VcfVOTNl353vSrDCLLJXmPk06wTjxrrjcBpXp5EOnYG_NjFZ6OvLFV1jSfS9tsz4qUxcWceqwQGk";
+const RFC7515_A4_JWK_Y: &str =
+    "ADSmRA43Z1DSNx_RvcLI87cdL07l6jQyyBXMoxVg_l2Th-x3S1WDhjDly79ajL4Kkd0AZMaZmh9ubmf63e3kyMj2";
+const RFC7515_A4_JWS: &str = "eyJhbGciOiJFUzUxMiJ9.UGF5bG9hZA.AdwMgeerwtHoh-l192l60hp9wAHZFVJbLfD_\
+     UxMi70cwnZOYaRI1bKPWROc-mZZqwqT2SI-KGDKB34XO0aw_7XdtAG8GaSwFKdCAPZgoXD2YBJZCPEX3xKpRwcdOO8Kp\
+     EHwJjyqOgzDO7iKvU8vcnwNrmxYbSW9ERBXukOXolLzeO_Jn";
+
+/// The keys of the test vectors of appendix A.2 of RFC6979, from their private scalar.
+fn rfc6979_key(alg: Algorithm) -> DeterministicKey {
+    let x = match alg {
+        Algorithm::ES256 => "C9AFA9D845BA75166B5C215767B1D6934E50C3DB36E89B127B8A622B120F6721",
+        Algorithm::ES384 => {
+            "6B9D3DAD2E1B8C1C05B19875B6659F4DE23C3B667BF297BA9AA47740787137D8\
+             96D5724E4C70A825F872C9EA60D2EDF5"
+        }
+        Algorithm::ES512 => {
+            "00FAD06DAA62BA3B25D2FB40133DA757205DE67F5BB0018FEE8C86E1B68C7E75\
+             CAA896EB32F1F47C70855836A6D16FCC1466F6D8FBEC67DB89EC0C08B0E996B8\
+             3538"
+        }
+    };
+    let group = EcGroup::from_curve_name(alg.curve()).unwrap();
+    let x = BigNum::from_hex_str(x).unwrap();
+    let mut public_key = EcPoint::new(&group).unwrap();
+    public_key
+        .mul_generator(&group, &x, &BigNumContext::new().unwrap())
+        .unwrap();
+    DeterministicKey::new(EcKey::from_private_components(&group, &x, &public_key).unwrap())
+}
+
+/// The JSON protected header of a JWS, as serialized.
+fn raw_header(jws: &DetachedJws) -> String {
+    let header = jws.to_string();
+    let header = header.split('.').next().unwrap();
+    String::from_utf8(base64_decode(header).unwrap()).unwrap()
+}
+
+#[test]
+fn rfc7515_a4_signature_verifies() {
+    let public_key = public_key_from_jwk(&json!({
+        "kty": "EC",
+        "crv": "P-521",
+        "x": RFC7515_A4_JWK_X,
+        "y": RFC7515_A4_JWK_Y,
+    }))
+    .unwrap();
+    let jws = DetachedJws::from_compact(RFC7515_A4_JWS).unwrap();
+    assert_eq!(jws.alg().unwrap(), Algorithm::ES512);
+    jws.verify(b"Payload", &public_key).unwrap();
+
+    let (signing_input, signature) = RFC7515_A4_JWS.rsplit_once('.').unwrap();
+    let signature = base64_decode(signature).unwrap();
+    assert_eq!(signature.len(), 132);
+    assert!(verify_ecdsa(
+        signing_input.as_bytes(),
+        &signature,
+        Algorithm::ES512,
+        &public_key
+    )
+    .unwrap());
+    assert!(!verify_ecdsa(
+        b"eyJhbGciOiJFUzUxMiJ9.UGF5bG9hZB",
+        &signature,
+        Algorithm::ES512,
+        &public_key
+    )
+    .unwrap());
+}
+
+#[test]
+fn rfc7515_a4_signing_input() {
+    let key = generate_key(Algorithm::ES512).unwrap();
+    let jws = get_jws(&json!({"alg": "ES512"}), b"Payload", &key).unwrap();
+    let (signing_input, signature) = jws.rsplit_once('.').unwrap();
+    assert_eq!(signing_input, "eyJhbGciOiJFUzUxMiJ9.UGF5bG9hZA");
+    let signature = base64_decode(signature).unwrap();
+    assert_eq!(signature.len(), 132);
+    assert!(verify_ecdsa(
+        signing_input.as_bytes(),
+        &signature,
+        Algorithm::ES512,
+        &SigningKey::public_key(&key).unwrap()
+    )
+    .unwrap());
+}
+
+#[test]
+fn signatures_have_the_fixed_length_of_the_curve() {
+    for alg in &[Algorithm::ES256, Algorithm::ES384, Algorithm::ES512] {
+        let key = generate_key(*alg).unwrap();
+        let public_key = SigningKey::public_key(&key).unwrap();
+        let mut padded = false;
+        for i in 0..200 {
+            let payload = i.to_string();
+            let signature = SigningKey::sign(&key, payload.as_bytes()).unwrap();
+            assert_eq!(signature.len(), 2 * alg.coordinate_len());
+            assert!(verify_ecdsa(payload.as_bytes(), &signature, *alg, &public_key).unwrap());
+            padded |= signature[0] == 0;
+        }
+        // `r` is below 2^512 for about half of the signatures on P-521
+        if *alg == Algorithm::ES512 {
+            assert!(padded, "No ES512 signature had a short `r`.");
+        }
+    }
+}
+
+/// Signatures of RFC6979 keys whose `r` or `s` are shorter than the curve, i.e. start with zeros.
+#[test]
+fn short_coordinates_are_padded() {
+    let cases = [
+        // `r` starts with a zero byte
+        (
+            Algorithm::ES256,
+            "281",
+            "ALkNq1sEQV-DwXI0LIRuA8vahDstB05Hg6HJdhx8ceEub04AAWxyXfDIroSzYjCqzraa-mJUQlUYUtjN-tZUZw",
+        ),
+        // `s` starts with a zero byte
+        (
+            Algorithm::ES256,
+            "192",
+            "6a2Te9vpXqiSUbyUSS6wwMQpS1NXZ-SxFGG1o6oxknsABsZ77XMYzcEc_dEVTXY_NSmIVsZfeWyAfv5_us5kwg",
+        ),
+        // `r` starts with two zero bytes, i.e. is 64 bytes long
+        (
+            Algorithm::ES512,
+            "129",
+            "AAAqdMYLVxXRmctrxSiFRwypV7lC-H-Nh_GSUCoJ0h8IWk23S0YppPaEhDL-g72QW_duai3L2IIUsocoGNuqbxF-\
+             AYx60Mz9x4HRiVOlV9lc50Y2eHyG0D7xHYkXCICVyL5nqlB07bj6nOijr5-h-tKyQ6F9N0SwfAJcymLuXYR28VV-",
+        ),
+        // `s` starts with two zero bytes
+        (
+            Algorithm::ES512,
+            "99",
+            "AU5rGqtKwV8PEY00hQiHetMa-qUQz6jgAPVNN35OY2lR-xWO80FkewUIKQA1yIFrGABdyMCZkz_PhZ0gMwuV0Yzu\
+             AADnSfoBZWuL6__HFW60OyLN2OMRQfawwqSxBdZluX6_iLI-EShheIKXodoAKi1yptD3Y2-TtWDnG61KiFm1WIOz",
+        ),
+    ];
+    for (alg, payload, expected) in &cases {
+        let key = rfc6979_key(*alg);
+        let signature = key.sign(payload.as_bytes()).unwrap();
+        assert_eq!(base64_encode(&signature), *expected);
+
+        let public_key = key.public_key().unwrap();
+        assert!(verify_ecdsa(payload.as_bytes(), &signature, *alg, &public_key).unwrap());
+        // Dropping the padding, as a DER to raw conversion forgetting it would, is rejected
+        let len = alg.coordinate_len();
+        let (r, s) = signature.split_at(len);
+        let unpadded: Vec<u8> = [r, s]
+            .iter()
+            .flat_map(|coordinate| coordinate.iter().skip_while(|byte| **byte == 0))
+            .copied()
+            .collect();
+        assert!(unpadded.len() < 2 * len);
+        assert!(verify_ecdsa(payload.as_bytes(), &unpadded, *alg, &public_key).is_err());
+    }
+}
+
+#[test]
+fn header_members_keep_their_order() {
+    let signer = Signer::new(rfc6979_key(Algorithm::ES512), KID).unwrap();
+    assert_eq!(
+        raw_header(&signer.sign(b"{}").unwrap()),
+        format!(r#"{{"alg":"ES512","kid":"{}"}}"#, KID)
+    );
+    let headers = [
+        Header::new("Idempotency-Key", "1"),
+        Header::new("X-Custom", "a"),
+    ];
+    assert_eq!(
+        raw_header(
+            &signer
+                .sign_request("POST", "/v3/payments", &headers, b"{}")
+                .unwrap()
+        ),
+        format!(
+            r#"{{"alg":"ES512","kid":"{}","tl_version":"2","tl_headers":"Idempotency-Key,X-Custom"}}"#,
+            KID
+        )
+    );
+
+    // A custom header is serialized in the order of its members, not sorted
+    let key = rfc6979_key(Algorithm::ES256);
+    let jws = get_jws(
+        &json!({"typ": "JOSE", "kid": KID, "alg": "ES256"}),
+        b"{}",
+        &key,
+    )
+    .unwrap();
+    let header = jws.split('.').next().unwrap();
+    assert_eq!(
+        String::from_utf8(base64_decode(header).unwrap()).unwrap(),
+        format!(r#"{{"typ":"JOSE","kid":"{}","alg":"ES256"}}"#, KID)
+    );
+}
+
+#[test]
+fn signatures_round_trip() {
+    for alg in &[Algorithm::ES256, Algorithm::ES384, Algorithm::ES512] {
+        let key = generate_key(*alg).unwrap();
+        let public_key = SigningKey::public_key(&key).unwrap();
+        let other_key = SigningKey::public_key(&generate_key(*alg).unwrap()).unwrap();
+        let signer = Signer::new(key, KID).unwrap();
+        assert_eq!(signer.alg(), *alg);
+
+        let payload = br#"{"amount_in_minor":100}"#;
+        let jws = signer.sign(payload).unwrap();
+        assert_eq!(jws.to_string().parse::<DetachedJws>().unwrap(), jws);
+        jws.verify(payload, &public_key).unwrap();
+        assert!(jws
+            .verify(br#"{"amount_in_minor":101}"#, &public_key)
+            .is_err());
+        assert!(jws.verify(payload, &other_key).is_err());
+        assert_eq!(
+            DetachedJws::from_compact(&jws.attach(payload).unwrap()).unwrap(),
+            jws
+        );
+    }
+}
+
+#[test]
+fn request_signatures_round_trip() {
+    let key = generate_key(Algorithm::ES512).unwrap();
+    let public_key = SigningKey::public_key(&key).unwrap();
+    let signer = Signer::new(key, KID).unwrap();
+    let body = br#"{"amount_in_minor":100}"#;
+    let jws = signer
+        .sign_request(
+            "post",
+            "/v3/payments",
+            &[
+                Header::new("Idempotency-Key", "1"),
+                Header::new("X-Custom", "a"),
+            ],
+            body,
+        )
+        .unwrap();
+
+    // Headers are matched ignoring case and order, extra ones being ignored
+    let received = [
+        Header::new("x-custom", "a"),
+        Header::new("Content-Type", "application/json"),
+        Header::new("idempotency-key", "1"),
+    ];
+    jws.verify_request("POST", "/v3/payments", &received, body, &public_key)
+        .unwrap();
+
+    assert!(jws
+        .verify_request("POST", "/v3/payouts", &received, body, &public_key)
+        .is_err());
+    assert!(jws
+        .verify_request("POST", "/v3/payments", &received[1..], body, &public_key)
+        .is_err());
+    assert!(jws
+        .verify_request(
+            "POST",
+            "/v3/payments",
+            &[
+                Header::new("X-Custom", "b"),
+                Header::new("Idempotency-Key", "1")
+            ],
+            body,
+            &public_key
+        )
+        .is_err());
+    assert!(jws
+        .verify_request("POST", "/v3/payments", &received, b"{}", &public_key)
+        .is_err());
+    // A legacy signature of the body alone is not a request signature
+    assert!(signer
+        .sign(body)
+        .unwrap()
+        .verify_request("POST", "/v3/payments", &received, body, &public_key)
+        .is_err());
+}
+
+const WEBHOOK_JKU: &str = "https://webhooks.truelayer.com/.well-known/jwks";
+
+/// A webhook signature of `body`, as sent by TrueLayer with `jku` in the JWS header.
+fn webhook_signature(key: &EcKey<Private>, jku: Option<&str>, body: &[u8]) -> DetachedJws {
+    let mut header = json!({
+        "alg": "ES512",
+        "kid": KID,
+        "tl_version": "2",
+        "tl_headers": "X-Tl-Webhook-Timestamp",
+    });
+    if let Some(jku) = jku {
+        header["jku"] = json!(jku);
+    }
+    let headers = [Header::new(
+        "X-Tl-Webhook-Timestamp",
+        "2021-01-01T00:00:00Z",
+    )];
+    let payload = v2_signing_payload("POST", "/webhooks", &headers, body);
+    let jws = get_jws(&header, &payload, key).unwrap();
+    let (header, rest) = jws.split_once('.').unwrap();
+    let (_, signature) = rest.split_once('.').unwrap();
+    format!("{}..{}", header, signature).parse().unwrap()
+}
+
+fn verify_webhook(verifier: &WebhookVerifier, signature: &DetachedJws, body: &[u8]) -> String {
+    let headers = [Header::new(
+        "X-Tl-Webhook-Timestamp",
+        "2021-01-01T00:00:00Z",
+    )];
+    match verifier.verify(signature, "POST", "/webhooks", &headers, body) {
+        Ok(()) => "valid".to_owned(),
+        Err(error) => error.to_string(),
+    }
+}
+
+#[test]
+fn webhook_signatures_are_verified() {
+    let key = generate_key(Algorithm::ES512).unwrap();
+    let jwks = json!({ "keys": [public_jwk(&key, KID).unwrap()] });
+    let verifier = WebhookVerifier::new(&jwks).unwrap();
+    let body = br#"{"type":"payment_executed"}"#;
+    let signature = webhook_signature(&key, Some(WEBHOOK_JKU), body);
+    assert_eq!(verify_webhook(&verifier, &signature, body), "valid");
+
+    // The signature still covers the request
+    assert!(verifier
+        .verify(&signature, "POST", "/webhooks", &[], body)
+        .is_err());
+    assert!(verify_webhook(&verifier, &signature, b"{}").starts_with("Invalid signature"));
+
+    // The key of another JWKS does not verify it
+    let other = generate_key(Algorithm::ES512).unwrap();
+    let other_jwks = json!({ "keys": [public_jwk(&other, KID).unwrap()] });
+    let other_verifier = WebhookVerifier::new(&other_jwks).unwrap();
+    assert_ne!(verify_webhook(&other_verifier, &signature, body), "valid");
+}
+
+#[test]
+fn webhook_signatures_need_an_allowed_jku() {
+    let key = generate_key(Algorithm::ES512).unwrap();
+    let jwks = json!({ "keys": [public_jwk(&key, KID).unwrap()] });
+    let body = b"{}";
+    let attacker = "https://attacker.example/.well-known/jwks";
+
+    let verifier = WebhookVerifier::new(&jwks).unwrap();
+    assert_eq!(
+        verify_webhook(&verifier, &webhook_signature(&key, None, body), body),
+        "The JWS header is missing `jku`."
+    );
+    assert_eq!(
+        verify_webhook(
+            &verifier,
+            &webhook_signature(&key, Some(attacker), body),
+            body
+        ),
+        format!(
+            "The `jku` {} is not allowed, expected one of {}.",
+            attacker,
+            TRUELAYER_WEBHOOK_JKUS.join(", ")
+        )
+    );
+
+    // The allowed `jku` replace TrueLayer's rather than being added to them
+    let verifier = verifier.with_allowed_jkus(vec![attacker.to_owned()]);
+    assert_eq!(
+        verify_webhook(
+            &verifier,
+            &webhook_signature(&key, Some(attacker), body),
+            body
+        ),
+        "valid"
+    );
+    assert_eq!(
+        verify_webhook(
+            &verifier,
+            &webhook_signature(&key, Some(WEBHOOK_JKU), body),
+            body
+        ),
+        format!(
+            "The `jku` {} is not allowed, expected one of {}.",
+            WEBHOOK_JKU, attacker
+        )
+    );
+}
+
+#[test]
+fn webhook_signatures_need_a_matching_key() {
+    let key = generate_key(Algorithm::ES512).unwrap();
+    let body = b"{}";
+    let signature = webhook_signature(&key, Some(WEBHOOK_JKU), body);
+
+    let jwks = json!({ "keys": [public_jwk(&key, "another-kid").unwrap()] });
+    assert_eq!(
+        verify_webhook(&WebhookVerifier::new(&jwks).unwrap(), &signature, body),
+        format!("The JWKS has no key with `kid` {}.", KID)
+    );
+
+    let mut jwk = public_jwk(&key, KID).unwrap();
+    jwk["alg"] = json!("ES256");
+    let jwks = json!({ "keys": [jwk] });
+    assert_eq!(
+        verify_webhook(&WebhookVerifier::new(&jwks).unwrap(), &signature, body),
+        format!(
+            "The JWS algorithm ES512 does not match the algorithm ES256 of the key {}.",
+            KID
+        )
+    );
+
+    assert!(WebhookVerifier::new(&json!([])).is_err());
+}
+
+#[test]
+fn check_the_public_key_of_the_certificate() {
+    let key = generate_key(Algorithm::ES512).unwrap();
+    let certificate = self_signed_certificate(&key, Algorithm::ES512, "tlsign", 1).unwrap();
+    let public_key = |certificate: &X509| certificate.public_key().unwrap().ec_key().unwrap();
+    let signer = Signer::new(key, KID).unwrap();
+    signer.check_public_key(&public_key(&certificate)).unwrap();
+
+    let other = generate_key(Algorithm::ES512).unwrap();
+    let other_certificate = self_signed_certificate(&other, Algorithm::ES512, "tlsign", 1).unwrap();
+    let error = signer
+        .check_public_key(&public_key(&other_certificate))
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        error,
+        format!(
+            "The private key does not match the certificate of kid {}: the public key of the \
+             private key has fingerprint {}, the one of the certificate {}.",
+            KID,
+            public_key_fingerprint(&signer.public_key().unwrap()).unwrap(),
+            public_key_fingerprint(&public_key(&other_certificate)).unwrap()
+        )
+    );
+}
+
+#[test]
+fn fingerprints_ignore_how_the_curve_is_encoded() {
+    let key = generate_key(Algorithm::ES256).unwrap();
+    let named = PKey::from_ec_key(SigningKey::public_key(&key).unwrap()).unwrap();
+
+    let mut group = EcGroup::from_curve_name(Algorithm::ES256.curve()).unwrap();
+    group.set_asn1_flag(Asn1Flag::EXPLICIT_CURVE);
+    let explicit = EcKey::from_public_key(&group, named.ec_key().unwrap().public_key()).unwrap();
+    let explicit_der = PKey::from_ec_key(explicit)
+        .unwrap()
+        .public_key_to_der()
+        .unwrap();
+    // The curve parameters are spelled out, rather than referred to by name
+    assert!(explicit_der.len() > named.public_key_to_der().unwrap().len());
+
+    let parsed = PKey::public_key_from_der(&explicit_der)
+        .unwrap()
+        .ec_key()
+        .unwrap();
+    assert_eq!(
+        public_key_fingerprint(&parsed).unwrap(),
+        public_key_fingerprint(&named.ec_key().unwrap()).unwrap()
+    );
+    Signer::new(key, KID)
+        .unwrap()
+        .check_public_key(&parsed)
+        .unwrap();
+}
+
+#[test]
+fn the_header_alg_must_match_the_key() {
+    let key = generate_key(Algorithm::ES256).unwrap();
+    assert!(get_jws(&json!({"alg": "ES512"}), b"{}", &key).is_err());
+    assert!(Signer::new(key, KID)
+        .unwrap()
+        .with_algorithm(Algorithm::ES384)
+        .is_err());
+}
+
+#[test]
+fn unencoded_payloads_round_trip() {
+    let key = generate_key(Algorithm::ES512).unwrap();
+    let public_key = SigningKey::public_key(&key).unwrap();
+    let signer = Signer::new(key, KID).unwrap().with_unencoded_payload();
+
+    let payload = br#"{"amount":1.5}"#;
+    let jws = signer.sign(payload).unwrap();
+    assert_eq!(
+        raw_header(&jws),
+        format!(
+            r#"{{"alg":"ES512","kid":"{}","b64":false,"crit":["b64"]}}"#,
+            KID
+        )
+    );
+    assert!(!jws.is_payload_encoded().unwrap());
+    let header = jws.to_string();
+    let header = header.split('.').next().unwrap();
+    assert_eq!(
+        jws.signing_input(payload).unwrap(),
+        format!("{}.{}", header, r#"{"amount":1.5}"#).into_bytes()
+    );
+    jws.verify(payload, &public_key).unwrap();
+    assert!(jws.verify(br#"{"amount":1.6}"#, &public_key).is_err());
+
+    assert!(Signer::new(generate_key(Algorithm::ES512).unwrap(), KID)
+        .unwrap()
+        .sign(payload)
+        .unwrap()
+        .is_payload_encoded()
+        .unwrap());
+
+    // Only a payload without `.` can be attached
+    assert!(jws.attach(payload).is_err());
+    let jws = signer.sign(br#"{"amount":1}"#).unwrap();
+    let compact = jws.attach(br#"{"amount":1}"#).unwrap();
+    assert_eq!(compact.split('.').nth(1), Some(r#"{"amount":1}"#));
+    assert_eq!(DetachedJws::from_compact(&compact).unwrap(), jws);
+}
+
+#[test]
+fn get_jws_signs_unencoded_payloads() {
+    let key = rfc6979_key(Algorithm::ES256);
+    let public_key = key.public_key().unwrap();
+    let header = json!({"alg": "ES256", "b64": false, "crit": ["b64"]});
+    // A payload with a `.` cannot be attached in compact serialization
+    assert!(get_jws(&header, b"{\"amount\":0.02}", &key).is_err());
+    let jws = get_jws(&header, b"{\"amount\":2}", &key).unwrap();
+    let (signing_input, signature) = jws.rsplit_once('.').unwrap();
+    assert!(signing_input.ends_with(".{\"amount\":2}"));
+    assert!(verify_ecdsa(
+        signing_input.as_bytes(),
+        &base64_decode(signature).unwrap(),
+        Algorithm::ES256,
+        &public_key
+    )
+    .unwrap());
+
+    // RFC7797 requires `b64` to be critical, and unknown critical parameters are rejected
+    assert!(get_jws(&json!({"alg": "ES256", "b64": false}), b"{}", &key).is_err());
+    assert!(get_jws(
+        &json!({"alg": "ES256", "b64": "false", "crit": ["b64"]}),
+        b"{}",
+        &key
+    )
+    .is_err());
+    assert!(get_jws(&json!({"alg": "ES256", "crit": ["exp"]}), b"{}", &key).is_err());
+    assert!(get_jws(
+        &json!({"alg": "ES256", "b64": true, "crit": ["b64"]}),
+        b"{}",
+        &key
+    )
+    .is_ok());
+}
+
+/// Checks a signature of appendix A.2 of RFC6979, `r` and `s` being hexadecimal.
+fn assert_rfc6979_signature(alg: Algorithm, message: &str, r: &str, s: &str) {
+    let len = alg.coordinate_len() as i32;
+    let expected = [
+        BigNum::from_hex_str(r).unwrap().to_vec_padded(len).unwrap(),
+        BigNum::from_hex_str(s).unwrap().to_vec_padded(len).unwrap(),
+    ]
+    .concat();
+    assert_eq!(rfc6979_key(alg).sign(message.as_bytes()).unwrap(), expected);
+}
+
+#[test]
+fn rfc6979_p256_sha256() {
+    assert_rfc6979_signature(
+        Algorithm::ES256,
+        "sample",
+        "EFD48B2AACB6A8FD1140DD9CD45E81D69D2C877B56AAF991C34D0EA84EAF3716",
+        "F7CB1C942D657C41D436C7A1B6E29F65F3E900DBB9AFF4064DC4AB2F843ACDA8",
+    );
+    assert_rfc6979_signature(
+        Algorithm::ES256,
+        "test",
+        "F1ABB023518351CD71D881567B1EA663ED3EFCF6C5132B354F28D3B0B7D38367",
+        "019F4113742A2B14BD25926B49C649155F267E60D3814B4C0CC84250E46F0083",
+    );
+}
+
+#[test]
+fn rfc6979_p384_sha384() {
+    assert_rfc6979_signature(
+        Algorithm::ES384,
+        "sample",
+        "94EDBB92A5ECB8AAD4736E56C691916B3F88140666CE9FA73D64C4EA95AD133C\
+         81A648152E44ACF96E36DD1E80FABE46",
+        "99EF4AEB15F178CEA1FE40DB2603138F130E740A19624526203B6351D0A3A94F\
+         A329C145786E679E7B82C71A38628AC8",
+    );
+    assert_rfc6979_signature(
+        Algorithm::ES384,
+        "test",
+        "8203B63D3C853E8D77227FB377BCF7B7B772E97892A80F36AB775D509D7A5FEB\
+         0542A7F0812998DA8F1DD3CA3CF023DB",
+        "DDD0760448D42D8A43AF45AF836FCE4DE8BE06B485E9B61B827C2F13173923E0\
+         6A739F040649A667BF3B828246BAA5A5",
+    );
+}
+
+#[test]
+fn rfc6979_p521_sha512() {
+    assert_rfc6979_signature(
+        Algorithm::ES512,
+        "sample",
+        "00C328FAFCBD79DD77850370C46325D987CB525569FB63C5D3BC53950E6D4C5F\
+         174E25A1EE9017B5D450606ADD152B534931D7D4E8455CC91F9B15BF05EC36E3\
+         77FA",
+        "00617CCE7CF5064806C467F678D3B4080D6F1CC50AF26CA209417308281B68AF\
+         282623EAA63E5B5C0723D8B8C37FF0777B1A20F8CCB1DCCC43997F1EE0E44DA4\
+         A67A",
+    );
+    assert_rfc6979_signature(
+        Algorithm::ES512,
+        "test",
+        "013E99020ABF5CEE7525D16B69B229652AB6BDF2AFFCAEF38773B4B7D08725F1\
+         0CDB93482FDCC54EDCEE91ECA4166B2A7C6265EF0CE2BD7051B7CEF945BABD47\
+         EE6D",
+        "01FBD0013C674AA79CB39849527916CE301C66EA7CE8B80682786AD60F98F7E7\
+         8A19CA69EFF5C57400E3B3A0AD66CE0978214D13BAF4E9AC60752F7B155E2DE4\
+         DCE3",
+    );
+}
+
+#[test]
+fn deterministic_request_signature_is_reproducible() {
+    let signer = Signer::new(rfc6979_key(Algorithm::ES256), KID).unwrap();
+    let jws = signer
+        .sign_request(
+            "POST",
+            "/v3/payments",
+            &[Header::new("Idempotency-Key", "1")],
+            br#"{"amount":100}"#,
+        )
+        .unwrap();
+    assert_eq!(
+        jws.to_string(),
+        "eyJhbGciOiJFUzI1NiIsImtpZCI6IjQ1ZmM3NWNmLTU2NDktNDEzNC04NGIzLTE5MmMyYzc4ZTk5MCIsInRs\
+         X3ZlcnNpb24iOiIyIiwidGxfaGVhZGVycyI6IklkZW1wb3RlbmN5LUtleSJ9..CBVIxH_IeOFTyG3V9munHLi\
+         D2dwP5fwCLINAdaX1j4As79ltGpfdiorOIpHi6hZmuqc7FcVlfhwxzswWIgqx4Q"
+    );
+}
@@ -0,0 +1,116 @@
+//! Signing with a key on a SoftHSM token, set up as in the README.
+//!
+//! Requires `softhsm2-util` and `pkcs11-tool`, and the path of the SoftHSM module, e.g.
+//! `TLSIGN_TEST_SOFTHSM=/usr/lib/softhsm/libsofthsm2.so cargo test --test pkcs11 -- --ignored`.
+#![cfg(unix)]
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+use tlsign::{Pkcs11Key, Signer, SigningKey};
+
+const KID: &str = "45fc75cf-5649-4134-84b3-192c2c78e990";
+
+/// A SoftHSM token labelled `tlsign` holding a P-521 key labelled `signing`, in a directory of its
+/// own, returning the path of the module.
+fn softhsm_token() -> PathBuf {
+    let module = PathBuf::from(std::env::var_os("TLSIGN_TEST_SOFTHSM").expect(
+        "Set TLSIGN_TEST_SOFTHSM to the path of the SoftHSM module, e.g. \
+         /usr/lib/softhsm/libsofthsm2.so.",
+    ));
+    let dir = std::env::temp_dir().join(format!("tlsign-softhsm-{}", std::process::id()));
+    let tokens = dir.join("tokens");
+    std::fs::create_dir_all(&tokens).unwrap();
+    let conf = dir.join("softhsm2.conf");
+    std::fs::write(
+        &conf,
+        format!("directories.tokendir = {}\n", tokens.display()),
+    )
+    .unwrap();
+    // Read by the module loaded in this process, as well as by the commands
+    std::env::set_var("SOFTHSM2_CONF", &conf);
+
+    run(Command::new("softhsm2-util").args([
+        "--init-token",
+        "--free",
+        "--label",
+        "tlsign",
+        "--pin",
+        "1234",
+        "--so-pin",
+        "5678",
+    ]));
+    run(Command::new("pkcs11-tool")
+        .arg("--module")
+        .arg(&module)
+        .args([
+            "--token-label",
+            "tlsign",
+            "--login",
+            "--pin",
+            "1234",
+            "--keypairgen",
+            "--key-type",
+            "EC:secp521r1",
+            "--label",
+            "signing",
+        ]));
+    module
+}
+
+fn run(command: &mut Command) {
+    let output = command.output().unwrap();
+    assert!(
+        output.status.success(),
+        "{:?} failed: {}",
+        command,
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+fn open(module: &Path) -> Pkcs11Key {
+    Pkcs11Key::open(module, "tlsign", "signing", "1234").unwrap()
+}
+
+#[test]
+#[ignore]
+fn softhsm() {
+    let module = softhsm_token();
+    sign_with_keys_on(&module);
+}
+
+fn sign_with_keys_on(module: &Path) {
+    // Checked while no key is open, as a token already logged in does not check the PIN again
+    assert!(Pkcs11Key::open(module, "tlsign", "signing", "0000").is_err());
+    assert!(Pkcs11Key::open(module, "tlsign", "missing", "1234").is_err());
+    assert!(Pkcs11Key::open(module, "missing", "signing", "1234").is_err());
+
+    let key = open(module);
+    let public_key = key.public_key().unwrap();
+    let signer = Signer::new(key, KID).unwrap();
+    assert_eq!(signer.alg().name(), "ES512");
+    let jws = signer.sign(b"{}").unwrap();
+    jws.verify(b"{}", &public_key).unwrap();
+
+    // Another key opened and dropped meanwhile must not finalise the module nor log out
+    let other = open(module);
+    other.sign(b"{}").unwrap();
+    drop(other);
+    signer
+        .sign(b"{}")
+        .unwrap()
+        .verify(b"{}", &public_key)
+        .unwrap();
+
+    // Nor must the key which initialised the module and logged in, when dropped first
+    let last = Signer::new(open(module), KID).unwrap();
+    drop(signer);
+    last.sign(b"{}")
+        .unwrap()
+        .verify(b"{}", &public_key)
+        .unwrap();
+
+    // Once every key is dropped, the module is initialised again
+    drop(last);
+    open(module).sign(b"{}").unwrap();
+}